@@ -0,0 +1,662 @@
+use std::f32::consts::PI;
+
+use clap::ValueEnum;
+use nalgebra::{Complex, Matrix3, SMatrix, SVector, SymmetricEigen, Vector3};
+use rayon::prelude::*;
+
+use crate::{
+    buffer::{plane_from_fn, Mosaic, RgbImage},
+    normalize::Normalized,
+};
+
+/// Values below this are considered black in alignment proxies
+const PROXY_FLOOR: f32 = 1.0 / 65536.0;
+
+/// Distance from the median, in stops, under which MTB ignores pixels
+const MTB_TOLERANCE: f32 = 0.1;
+
+/// Proxies are halved until they fit, for phase correlation
+const MAX_FFT_SIZE: usize = 512;
+
+/// Patches per side used to estimate a homography
+const GRID: usize = 4;
+
+/// A position in the reference frame, and the same scene point in another frame
+pub type PointPair = ((f64, f64), (f64, f64));
+
+/// How frames are registered onto the first one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum AlignMethod {
+    /// Frames are used as they are
+    #[default]
+    None,
+    /// Median threshold bitmaps, insensitive to exposure differences
+    Mtb,
+    /// Phase correlation of log luminance
+    Phase,
+}
+
+/// Maps positions in the reference frame to the same scene point in another frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transform {
+    /// Whole pixels, multiples of the CFA pattern size for mosaics
+    Shift {
+        dx: isize,
+        dy: isize,
+    },
+    Homography(Matrix3<f64>),
+}
+
+impl Transform {
+    pub fn map(&self, x: f64, y: f64) -> (f64, f64) {
+        match self {
+            Transform::Shift { dx, dy } => (x + *dx as f64, y + *dy as f64),
+            Transform::Homography(h) => {
+                let p = h * Vector3::new(x, y, 1.0);
+                (p.x / p.z, p.y / p.z)
+            }
+        }
+    }
+}
+
+/// Registers frames of the same scene before they are combined
+#[derive(Debug, Clone, Default)]
+pub struct Aligner {
+    pub method: AlignMethod,
+    /// Fit a homography on local translations instead of a single translation
+    pub homography: bool,
+}
+
+impl Aligner {
+    /// Warp every frame after the first one onto it
+    pub fn align(&self, frames: &mut [Normalized]) {
+        if self.method == AlignMethod::None {
+            return;
+        }
+        let Some((reference, others)) = frames.split_first_mut() else {
+            return;
+        };
+        let reference_proxy = Proxy::new(reference);
+        if (reference_proxy.width == 0) | (reference_proxy.height == 0) {
+            return;
+        }
+        for frame in others {
+            let transform = self.estimate(&reference_proxy, &Proxy::new(frame));
+            *frame = warp(frame, transform);
+        }
+    }
+
+    /// Transform from positions in the reference to positions in the frame, in full resolution pixels
+    pub fn estimate(&self, reference: &Proxy, frame: &Proxy) -> Transform {
+        let (pw, ph) = reference.period;
+        let (dx, dy) = self.shift(reference, frame);
+        let shift = Transform::Shift {
+            dx: dx * pw as isize,
+            dy: dy * ph as isize,
+        };
+
+        let patch_width = reference.width / GRID;
+        let patch_height = reference.height / GRID;
+        if !self.homography || (patch_width < 16) || (patch_height < 16) {
+            return shift;
+        }
+
+        // Local shift of every patch, on top of the global one
+        let full = |x: f64, y: f64| {
+            (
+                x * pw as f64 + (pw - 1) as f64 / 2.0,
+                y * ph as f64 + (ph - 1) as f64 / 2.0,
+            )
+        };
+        let mut points = Vec::with_capacity(GRID * GRID);
+        for gy in 0..GRID {
+            for gx in 0..GRID {
+                let (x0, y0) = ((gx * patch_width) as isize, (gy * patch_height) as isize);
+                let a = reference.crop(x0, y0, patch_width, patch_height);
+                let b = frame.crop(x0 + dx, y0 + dy, patch_width, patch_height);
+                let (lx, ly) = self.shift(&a, &b);
+                let cx = x0 as f64 + patch_width as f64 / 2.0;
+                let cy = y0 as f64 + patch_height as f64 / 2.0;
+                points.push((
+                    full(cx, cy),
+                    full(cx + (dx + lx) as f64, cy + (dy + ly) as f64),
+                ));
+            }
+        }
+
+        match fit_homography(&points) {
+            Some(h) => Transform::Homography(h),
+            None => shift,
+        }
+    }
+
+    /// Translation of `frame` relative to `reference`, in proxy pixels
+    fn shift(&self, reference: &Proxy, frame: &Proxy) -> (isize, isize) {
+        match self.method {
+            AlignMethod::None => (0, 0),
+            AlignMethod::Mtb => mtb_shift(reference, frame),
+            AlignMethod::Phase => phase_shift(reference, frame),
+        }
+    }
+}
+
+/// Log luminance of a frame, one pixel per CFA pattern for mosaics
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    pub width: usize,
+    pub height: usize,
+    /// Full resolution pixels per proxy pixel
+    pub period: (usize, usize),
+    pub data: Vec<f32>,
+}
+
+impl Proxy {
+    pub fn new(frame: &Normalized) -> Proxy {
+        let log = |value: f32| value.max(PROXY_FLOOR).log2();
+        match frame {
+            Normalized::Mosaic(mosaic) => {
+                let (pw, ph) = (mosaic.cfa.width.max(1), mosaic.cfa.height.max(1));
+                let (width, height) = (mosaic.width / pw, mosaic.height / ph);
+                let data = plane_from_fn(width, height, |x, y| {
+                    let mut sum = 0.0;
+                    for dy in 0..ph {
+                        for dx in 0..pw {
+                            sum += mosaic.at(x * pw + dx, y * ph + dy);
+                        }
+                    }
+                    log(sum / (pw * ph) as f32)
+                });
+                Proxy {
+                    width,
+                    height,
+                    period: (pw, ph),
+                    data,
+                }
+            }
+            Normalized::Linear(image) => Proxy {
+                width: image.width,
+                height: image.height,
+                period: (1, 1),
+                data: plane_from_fn(image.width, image.height, |x, y| {
+                    let (r, g, b) = image.pixel(x, y);
+                    log((r + g + b) / 3.0)
+                }),
+            },
+        }
+    }
+
+    /// Value at given position, clamped to the edges
+    pub fn at(&self, x: isize, y: isize) -> f32 {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        self.data[self.width * y + x]
+    }
+
+    /// Part of the proxy starting at (x0, y0), edges repeated outside
+    pub fn crop(&self, x0: isize, y0: isize, width: usize, height: usize) -> Proxy {
+        Proxy {
+            width,
+            height,
+            period: self.period,
+            data: plane_from_fn(width, height, |x, y| {
+                self.at(x0 + x as isize, y0 + y as isize)
+            }),
+        }
+    }
+
+    /// Half size, averaging 2x2 blocks
+    fn half(&self) -> Proxy {
+        let (width, height) = (self.width / 2, self.height / 2);
+        Proxy {
+            width,
+            height,
+            period: (self.period.0 * 2, self.period.1 * 2),
+            data: plane_from_fn(width, height, |x, y| {
+                let (x, y) = (2 * x as isize, 2 * y as isize);
+                (self.at(x, y) + self.at(x + 1, y) + self.at(x, y + 1) + self.at(x + 1, y + 1))
+                    / 4.0
+            }),
+        }
+    }
+
+    fn median(&self) -> f32 {
+        let mut values = self.data.clone();
+        if values.is_empty() {
+            return 0.0;
+        }
+        let middle = values.len() / 2;
+        *values.select_nth_unstable_by(middle, f32::total_cmp).1
+    }
+}
+
+/// Ward's median threshold bitmap alignment: a coarse to fine search over a pyramid, one pixel at a time
+pub fn mtb_shift(reference: &Proxy, frame: &Proxy) -> (isize, isize) {
+    let mut pyramid = vec![(reference.clone(), frame.clone())];
+    while let Some((a, b)) = pyramid.last() {
+        if (a.width.min(a.height) < 64) | (pyramid.len() >= 8) {
+            break;
+        }
+        let next = (a.half(), b.half());
+        pyramid.push(next);
+    }
+
+    let (mut dx, mut dy) = (0isize, 0isize);
+    for (a, b) in pyramid.iter().rev() {
+        dx *= 2;
+        dy *= 2;
+        let (bits_a, mask_a) = bitmaps(a);
+        let (bits_b, mask_b) = bitmaps(b);
+
+        let mut best = (usize::MAX, dx, dy);
+        for sy in -1..=1 {
+            for sx in -1..=1 {
+                let (tx, ty) = (dx + sx, dy + sy);
+                let errors = (0..a.height)
+                    .into_par_iter()
+                    .map(|y| {
+                        let fy = y as isize + ty;
+                        if (fy < 0) | (fy >= b.height as isize) {
+                            return 0;
+                        }
+                        (0..a.width)
+                            .filter(|&x| {
+                                let fx = x as isize + tx;
+                                if (fx < 0) | (fx >= b.width as isize) {
+                                    return false;
+                                }
+                                let i = a.width * y + x;
+                                let j = b.width * fy as usize + fx as usize;
+                                mask_a[i] & mask_b[j] & (bits_a[i] != bits_b[j])
+                            })
+                            .count()
+                    })
+                    .sum::<usize>();
+                if errors < best.0 {
+                    best = (errors, tx, ty);
+                }
+            }
+        }
+        (dx, dy) = (best.1, best.2);
+    }
+    (dx, dy)
+}
+
+/// Pixels above the median, and pixels far enough from it to be trusted
+fn bitmaps(proxy: &Proxy) -> (Vec<bool>, Vec<bool>) {
+    let median = proxy.median();
+    let bits = proxy.data.iter().map(|&value| value > median).collect();
+    let mask = proxy
+        .data
+        .iter()
+        .map(|&value| (value - median).abs() > MTB_TOLERANCE)
+        .collect();
+    (bits, mask)
+}
+
+/// Translation from the peak of the normalized cross-power spectrum of the central square of both proxies
+pub fn phase_shift(reference: &Proxy, frame: &Proxy) -> (isize, isize) {
+    let (mut a, mut b) = (reference.clone(), frame.clone());
+    let mut factor = 1;
+    while a.width.min(a.height) > MAX_FFT_SIZE {
+        (a, b) = (a.half(), b.half());
+        factor *= 2;
+    }
+
+    let side = a.width.min(a.height);
+    if side < 8 {
+        return (0, 0);
+    }
+    // Largest power of two that fits
+    let n: usize = 1 << (usize::BITS - 1 - side.leading_zeros());
+    let x0 = ((a.width - n) / 2) as isize;
+    let y0 = ((a.height - n) / 2) as isize;
+
+    let spectrum_a = spectrum(&a.crop(x0, y0, n, n));
+    let spectrum_b = spectrum(&b.crop(x0, y0, n, n));
+    let mut cross: Vec<Complex<f32>> = spectrum_a
+        .par_iter()
+        .zip(&spectrum_b)
+        .map(|(a, b)| {
+            let product = a.conj() * b;
+            product / product.norm().max(f32::EPSILON)
+        })
+        .collect();
+    fft2(&mut cross, n, true);
+
+    let peak = cross
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.re.total_cmp(&b.re))
+        .map_or(0, |(index, _)| index);
+    let wrap = |position: usize| {
+        if position > n / 2 {
+            position as isize - n as isize
+        } else {
+            position as isize
+        }
+    };
+    (wrap(peak % n) * factor, wrap(peak / n) * factor)
+}
+
+/// Fourier transform of a square proxy, mean removed and Hann windowed
+fn spectrum(proxy: &Proxy) -> Vec<Complex<f32>> {
+    let n = proxy.width;
+    let mean = proxy.data.iter().sum::<f32>() / proxy.data.len() as f32;
+    let hann = |i: usize| 0.5 - 0.5 * (2.0 * PI * i as f32 / n as f32).cos();
+    let mut data: Vec<Complex<f32>> = proxy
+        .data
+        .iter()
+        .enumerate()
+        .map(|(index, value)| Complex::new((value - mean) * hann(index % n) * hann(index / n), 0.0))
+        .collect();
+    fft2(&mut data, n, false);
+    data
+}
+
+/// In-place 2D FFT of an n x n power of two square, rows then columns. The inverse is not scaled.
+fn fft2(data: &mut [Complex<f32>], n: usize, inverse: bool) {
+    data.par_chunks_mut(n).for_each(|row| fft(row, inverse));
+    let mut transposed = plane_from_fn(n, n, |x, y| data[n * x + y]);
+    transposed
+        .par_chunks_mut(n)
+        .for_each(|column| fft(column, inverse));
+    for (index, value) in data.iter_mut().enumerate() {
+        *value = transposed[n * (index % n) + index / n];
+    }
+}
+
+/// In-place iterative radix-2 FFT
+fn fft(data: &mut [Complex<f32>], inverse: bool) {
+    let n = data.len();
+    let bits = n.trailing_zeros();
+    for i in 0..n {
+        let j = i.reverse_bits() >> (usize::BITS - bits);
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut length = 2;
+    while length <= n {
+        let angle = sign * 2.0 * PI / length as f32;
+        let step = Complex::new(angle.cos(), angle.sin());
+        for chunk in data.chunks_mut(length) {
+            let mut twiddle = Complex::new(1.0, 0.0);
+            let (low, high) = chunk.split_at_mut(length / 2);
+            for (u, v) in low.iter_mut().zip(high.iter_mut()) {
+                let t = *v * twiddle;
+                (*u, *v) = (*u + t, *u - t);
+                twiddle *= step;
+            }
+        }
+        length <<= 1;
+    }
+}
+
+/// Least squares homography from point pairs (reference, frame), refitted once without outliers
+pub fn fit_homography(points: &[PointPair]) -> Option<Matrix3<f64>> {
+    let h = fit_dlt(points)?;
+
+    let residual = |((x, y), (u, v)): &PointPair| {
+        let (mx, my) = Transform::Homography(h).map(*x, *y);
+        ((mx - u).powi(2) + (my - v).powi(2)).sqrt()
+    };
+    let mut residuals: Vec<f64> = points.iter().map(residual).collect();
+    residuals.sort_unstable_by(f64::total_cmp);
+    let limit = 3.0 * residuals[residuals.len() / 2] + 1.0;
+
+    let inliers: Vec<_> = points
+        .iter()
+        .copied()
+        .filter(|point| residual(point) <= limit)
+        .collect();
+    if inliers.len() < points.len() {
+        fit_dlt(&inliers)
+    } else {
+        Some(h)
+    }
+}
+
+/// Normalized direct linear transform, needs at least 4 pairs
+fn fit_dlt(points: &[PointPair]) -> Option<Matrix3<f64>> {
+    if points.len() < 4 {
+        return None;
+    }
+    let from = normalizing(points.iter().map(|(a, _)| *a));
+    let to = normalizing(points.iter().map(|(_, b)| *b));
+
+    let mut ata = SMatrix::<f64, 9, 9>::zeros();
+    for ((x, y), (u, v)) in points {
+        let a = from * Vector3::new(*x, *y, 1.0);
+        let b = to * Vector3::new(*u, *v, 1.0);
+        let (x, y, u, v) = (a.x, a.y, b.x, b.y);
+        let rows = [
+            [-x, -y, -1.0, 0.0, 0.0, 0.0, u * x, u * y, u],
+            [0.0, 0.0, 0.0, -x, -y, -1.0, v * x, v * y, v],
+        ];
+        for row in rows {
+            let row = SVector::<f64, 9>::from_row_slice(&row);
+            ata += row * row.transpose();
+        }
+    }
+
+    // Eigenvector of the smallest eigenvalue
+    let eigen = SymmetricEigen::new(ata);
+    let solution: SVector<f64, 9> = eigen
+        .eigenvectors
+        .column(eigen.eigenvalues.imin())
+        .into_owned();
+    let normalized = Matrix3::from_row_slice(solution.as_slice());
+    let h = to.try_inverse()? * normalized * from;
+    let scale = h[(2, 2)];
+    if scale.abs() < f64::EPSILON {
+        return None;
+    }
+    let h = h / scale;
+    h.iter().all(|value| value.is_finite()).then_some(h)
+}
+
+/// Similarity moving points to their centroid, at an average distance of √2
+fn normalizing(points: impl Iterator<Item = (f64, f64)> + Clone) -> Matrix3<f64> {
+    let count = points.clone().count().max(1) as f64;
+    let (cx, cy) = points
+        .clone()
+        .fold((0.0, 0.0), |(sx, sy), (x, y)| (sx + x, sy + y));
+    let (cx, cy) = (cx / count, cy / count);
+    let distance = points
+        .map(|(x, y)| ((x - cx).powi(2) + (y - cy).powi(2)).sqrt())
+        .sum::<f64>()
+        / count;
+    let s = if distance > f64::EPSILON {
+        std::f64::consts::SQRT_2 / distance
+    } else {
+        1.0
+    };
+    Matrix3::new(s, 0.0, -s * cx, 0.0, s, -s * cy, 0.0, 0.0, 1.0)
+}
+
+/// Frame resampled on the reference grid. Mosaics only take samples from photosites of the same color.
+pub fn warp(frame: &Normalized, transform: Transform) -> Normalized {
+    match frame {
+        Normalized::Mosaic(mosaic) => Normalized::Mosaic(Box::new(warp_mosaic(mosaic, transform))),
+        Normalized::Linear(image) => {
+            let sample = |plane: &[f32], x: usize, y: usize| match transform {
+                Transform::Shift { dx, dy } => {
+                    let sx = inside(x as isize + dx, image.width, 1);
+                    let sy = inside(y as isize + dy, image.height, 1);
+                    plane[image.width * sy + sx]
+                }
+                Transform::Homography(_) => {
+                    let (u, v) = transform.map(x as f64, y as f64);
+                    bilinear(plane, image.width, image.height, u as f32, v as f32)
+                }
+            };
+            Normalized::Linear(RgbImage::from_fn(image.width, image.height, |x, y| {
+                (
+                    sample(&image.red, x, y),
+                    sample(&image.green, x, y),
+                    sample(&image.blue, x, y),
+                )
+            }))
+        }
+    }
+}
+
+fn warp_mosaic(mosaic: &Mosaic, transform: Transform) -> Mosaic {
+    let (pw, ph) = (mosaic.cfa.width.max(1), mosaic.cfa.height.max(1));
+    let data = plane_from_fn(mosaic.width, mosaic.height, |x, y| match transform {
+        Transform::Shift { dx, dy } => mosaic.at(
+            inside(x as isize + dx, mosaic.width, pw),
+            inside(y as isize + dy, mosaic.height, ph),
+        ),
+        Transform::Homography(_) => {
+            let (u, v) = transform.map(x as f64, y as f64);
+            let color = mosaic.color_at(x, y);
+            let (ru, rv) = (u.round() as isize, v.round() as isize);
+
+            // Nearest photosite of the same color
+            let mut best = (
+                f64::INFINITY,
+                inside(ru, mosaic.width, pw),
+                inside(rv, mosaic.height, ph),
+            );
+            for oy in -(ph as isize / 2)..=(ph as isize / 2) {
+                for ox in -(pw as isize / 2)..=(pw as isize / 2) {
+                    let sx = inside(ru + ox, mosaic.width, pw);
+                    let sy = inside(rv + oy, mosaic.height, ph);
+                    let distance = (sx as f64 - u).powi(2) + (sy as f64 - v).powi(2);
+                    if (mosaic.color_at(sx, sy) == color) && (distance < best.0) {
+                        best = (distance, sx, sy);
+                    }
+                }
+            }
+            mosaic.at(best.1, best.2)
+        }
+    });
+    Mosaic {
+        width: mosaic.width,
+        height: mosaic.height,
+        cfa: mosaic.cfa.clone(),
+        data,
+    }
+}
+
+/// Bring a position back into [0, size) by whole periods, so it keeps its place in the CFA pattern
+fn inside(position: isize, size: usize, period: usize) -> usize {
+    let (size, period) = (size as isize, period as isize);
+    let mut position = position;
+    if position < 0 {
+        position += (-position + period - 1) / period * period;
+    }
+    if position >= size {
+        position -= ((position - size) / period + 1) * period;
+    }
+    position.clamp(0, (size - 1).max(0)) as usize
+}
+
+/// Bilinear interpolation with edges repeated
+fn bilinear(plane: &[f32], width: usize, height: usize, x: f32, y: f32) -> f32 {
+    let at = |x: isize, y: isize| plane[width * inside(y, height, 1) + inside(x, width, 1)];
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as isize, y0 as isize);
+    let top = at(x0, y0) * (1.0 - fx) + at(x0 + 1, y0) * fx;
+    let bottom = at(x0, y0 + 1) * (1.0 - fx) + at(x0 + 1, y0 + 1) * fx;
+    top * (1.0 - fy) + bottom * fy
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demosaic::tests::{mosaic, BAYER, X_TRANS};
+
+    /// Log luminance of a textured scene, `stops` brighter, seen with the given translation
+    fn proxy(size: usize, (dx, dy): (isize, isize), stops: f32) -> Proxy {
+        let scene = |x: isize, y: isize| {
+            // Smooth shapes, and fine detail from a hash of the position
+            let (fx, fy) = (x as f32, y as f32);
+            let hash = (x.wrapping_mul(73_856_093) ^ y.wrapping_mul(19_349_663)) as u32;
+            (0.31 * fx).sin()
+                + (0.17 * fy).cos()
+                + (0.05 * (fx + 2.0 * fy)).sin()
+                + (hash.wrapping_mul(2_654_435_761) >> 16) as f32 / 65536.0
+        };
+        Proxy {
+            width: size,
+            height: size,
+            period: (1, 1),
+            data: plane_from_fn(size, size, |x, y| {
+                scene(x as isize - dx, y as isize - dy) + stops
+            }),
+        }
+    }
+
+    #[test]
+    fn mtb_recovers_a_shift() {
+        let reference = proxy(128, (0, 0), 0.0);
+        for stops in [0.0, 2.0] {
+            let frame = proxy(128, (3, -2), stops);
+            assert_eq!(mtb_shift(&reference, &frame), (3, -2), "{stops} stops");
+        }
+    }
+
+    #[test]
+    fn phase_correlation_recovers_a_shift() {
+        let reference = proxy(128, (0, 0), 0.0);
+        for stops in [0.0, -3.0] {
+            let frame = proxy(128, (-5, 7), stops);
+            assert_eq!(phase_shift(&reference, &frame), (-5, 7), "{stops} stops");
+        }
+    }
+
+    #[test]
+    fn homography_of_exact_point_pairs() {
+        let h = Matrix3::new(1.02, 0.03, 5.0, -0.01, 0.98, -3.0, 1e-5, -2e-5, 1.0);
+        let mut points = Vec::new();
+        for y in 0..GRID {
+            for x in 0..GRID {
+                let (x, y) = (x as f64 * 100.0, y as f64 * 80.0);
+                points.push(((x, y), Transform::Homography(h).map(x, y)));
+            }
+        }
+        let fitted = fit_homography(&points).unwrap();
+        assert!((fitted - h).abs().max() < 1e-9, "{fitted}");
+    }
+
+    #[test]
+    fn warped_mosaics_keep_their_colors() {
+        // Small rotation and translation
+        let angle = 0.02f64;
+        let h = Matrix3::new(
+            angle.cos(),
+            -angle.sin(),
+            3.4,
+            angle.sin(),
+            angle.cos(),
+            -1.7,
+            0.0,
+            0.0,
+            1.0,
+        );
+        for pattern in BAYER.into_iter().chain([X_TRANS]) {
+            // Every sample is the index of its color
+            let frame = mosaic(pattern, 30, 24, |_, _| [0.0, 1.0, 2.0]);
+            for transform in [
+                Transform::Homography(h),
+                Transform::Shift { dx: 6, dy: -12 },
+            ] {
+                let warped = warp_mosaic(&frame, transform);
+                for y in 0..warped.height {
+                    for x in 0..warped.width {
+                        assert_eq!(
+                            warped.at(x, y),
+                            warped.color_at(x, y) as f32,
+                            "{pattern}: ({x}, {y}) with {transform:?}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+use clap::ValueEnum;
+
+use crate::{
+    error::{Raw2ExrError, Result},
+    pipeline::Report,
+    Pipeline,
+};
+
+/// File extensions picked up when listing directories
+pub const RAW_EXTENSIONS: [&str; 27] = [
+    "3fr", "ari", "arw", "cr2", "crw", "dcr", "dcs", "dng", "erf", "iiq", "kdc", "mdc", "mef",
+    "mos", "mrw", "nef", "nrw", "orf", "pef", "raf", "raw", "rw2", "rwl", "sr2", "srf", "srw",
+    "x3f",
+];
+
+/// What to do when an output file is already there
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ExistingPolicy {
+    /// Leave the existing file alone and move on
+    Skip,
+    /// Replace the existing file
+    #[default]
+    Overwrite,
+}
+
+/// A single raw file to convert, and where to write it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Job {
+    pub raw: PathBuf,
+    pub exr: PathBuf,
+}
+
+/// What happened to a job
+#[derive(Debug)]
+pub enum Outcome {
+    Converted(Report),
+    Skipped,
+    Failed(Raw2ExrError),
+}
+
+/// Conversion of many files at once
+#[derive(Debug, Clone)]
+pub struct Batch {
+    /// Where outputs go, next to their input if `None`
+    pub output_dir: Option<PathBuf>,
+    /// Output file name, `{stem}`, `{ext}` and `{dir}` are replaced by the input's file stem, extension and directory relative to the listed directory.
+    ///
+    /// `{dir}` is `.` when outputs go next to their input, as they are already in that directory.
+    pub template: String,
+    /// Also list subdirectories of input directories
+    pub recursive: bool,
+    pub existing: ExistingPolicy,
+    /// How many files are converted at the same time
+    pub parallel_files: usize,
+    /// Jobs are merged or stacked into the output of the first one, so other outputs are never written
+    pub combined: bool,
+}
+
+impl Default for Batch {
+    fn default() -> Self {
+        Batch {
+            output_dir: None,
+            template: "{stem}.exr".to_string(),
+            recursive: false,
+            existing: ExistingPolicy::default(),
+            parallel_files: 1,
+            combined: false,
+        }
+    }
+}
+
+impl Batch {
+    /// Expand files, directories and glob patterns into jobs. Fails if two inputs would be written to the same output, unless they are combined.
+    pub fn jobs(&self, inputs: &[PathBuf]) -> Result<Vec<Job>> {
+        let mut jobs = Vec::new();
+
+        for input in inputs {
+            if input.is_dir() {
+                let mut files = Vec::new();
+                list_raws(input, self.recursive, &mut files)?;
+                files.sort();
+                for file in files {
+                    let relative = file
+                        .parent()
+                        .and_then(|parent| parent.strip_prefix(input).ok())
+                        .unwrap_or(Path::new(""))
+                        .to_path_buf();
+                    jobs.push(self.job(file, &relative));
+                }
+            } else if input.exists() {
+                jobs.push(self.job(input.clone(), Path::new("")));
+            } else {
+                // Not a path on disk, try it as a glob pattern
+                let pattern = input.to_string_lossy();
+                let paths = glob::glob(&pattern)
+                    .map_err(|e| Raw2ExrError::InvalidInput(format!("{pattern}: {e}")))?;
+                let mut matched = false;
+                for path in paths {
+                    let path = path.map_err(|e| Raw2ExrError::Io {
+                        path: e.path().to_path_buf(),
+                        source: e.into_error(),
+                    })?;
+                    if path.is_file() {
+                        jobs.push(self.job(path, Path::new("")));
+                        matched = true;
+                    }
+                }
+                if !matched {
+                    return Err(Raw2ExrError::InvalidInput(format!(
+                        "{pattern} matches no file"
+                    )));
+                }
+            }
+        }
+
+        if !self.combined {
+            check_outputs(&jobs)?;
+        }
+        Ok(jobs)
+    }
+
+    /// Output path of an input file found in `relative_dir` of a listed directory
+    fn job(&self, raw: PathBuf, relative_dir: &Path) -> Job {
+        let stem = raw.file_stem().unwrap_or_default().to_string_lossy();
+        let extension = raw.extension().unwrap_or_default().to_string_lossy();
+        let dir = if relative_dir.as_os_str().is_empty() || self.output_dir.is_none() {
+            ".".into()
+        } else {
+            relative_dir.to_string_lossy()
+        };
+        let name = self
+            .template
+            .replace("{stem}", &stem)
+            .replace("{ext}", &extension)
+            .replace("{dir}", &dir);
+
+        let exr = match &self.output_dir {
+            Some(dir) => dir.join(name),
+            None => raw.parent().unwrap_or(Path::new("")).join(name),
+        };
+        Job { raw, exr }
+    }
+
+    /// Convert a single job, honoring the existing file policy
+    pub fn run_job(&self, pipeline: &Pipeline, job: &Job) -> Outcome {
+        if let Some(outcome) = self.prepare(&job.exr) {
+            return outcome;
+        }
+
+        match pipeline.convert(&job.raw, &job.exr) {
+            Ok(report) => Outcome::Converted(report),
+            Err(e) => Outcome::Failed(e),
+        }
+    }
+
+    /// Merge the raw files of all jobs into the output of the first one, honoring the existing file policy
+    pub fn run_merged(&self, pipeline: &Pipeline, jobs: &[Job]) -> Outcome {
+        self.run_combined(jobs, |raws, exr| pipeline.convert_merged(raws, exr))
+    }
+
+    /// Stack the raw files of all jobs into the output of the first one, honoring the existing file policy
+    pub fn run_stacked(&self, pipeline: &Pipeline, jobs: &[Job]) -> Outcome {
+        self.run_combined(jobs, |raws, exr| pipeline.convert_stacked(raws, exr))
+    }
+
+    fn run_combined(
+        &self,
+        jobs: &[Job],
+        convert: impl FnOnce(&[PathBuf], &Path) -> Result<Report>,
+    ) -> Outcome {
+        let Some(first) = jobs.first() else {
+            return Outcome::Failed(Raw2ExrError::InvalidInput("no input files".to_string()));
+        };
+        if let Some(outcome) = self.prepare(&first.exr) {
+            return outcome;
+        }
+
+        let raws: Vec<PathBuf> = jobs.iter().map(|job| job.raw.clone()).collect();
+        match convert(&raws, &first.exr) {
+            Ok(report) => Outcome::Converted(report),
+            Err(e) => Outcome::Failed(e),
+        }
+    }
+
+    /// Outcome for an output that must not be written, or its directory couldn't be created
+    fn prepare(&self, exr: &Path) -> Option<Outcome> {
+        if (self.existing == ExistingPolicy::Skip) && exr.exists() {
+            return Some(Outcome::Skipped);
+        }
+
+        if let Some(parent) = exr.parent() {
+            if let Err(source) = fs::create_dir_all(parent) {
+                return Some(Outcome::Failed(Raw2ExrError::Io {
+                    path: parent.to_path_buf(),
+                    source,
+                }));
+            }
+        }
+        None
+    }
+
+    /// Convert all jobs, up to `parallel_files` at a time. A failure doesn't stop the others.
+    ///
+    /// Outcomes are in the same order as jobs. Each file is still processed in parallel internally, on the shared rayon thread pool.
+    pub fn run(&self, pipeline: &Pipeline, jobs: &[Job]) -> Vec<Outcome> {
+        let workers = self.parallel_files.clamp(1, jobs.len().max(1));
+        if workers == 1 {
+            return jobs.iter().map(|job| self.run_job(pipeline, job)).collect();
+        }
+
+        let next = AtomicUsize::new(0);
+        let mut outcomes: Vec<(usize, Outcome)> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut done = Vec::new();
+                        loop {
+                            let index = next.fetch_add(1, Ordering::Relaxed);
+                            let Some(job) = jobs.get(index) else {
+                                break;
+                            };
+                            done.push((index, self.run_job(pipeline, job)));
+                        }
+                        done
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect()
+        });
+
+        outcomes.sort_by_key(|(index, _)| *index);
+        outcomes.into_iter().map(|(_, outcome)| outcome).collect()
+    }
+}
+
+/// Make sure no two jobs write the same file, which would silently replace one output or have two threads write it at once
+fn check_outputs(jobs: &[Job]) -> Result<()> {
+    let mut outputs: HashMap<&Path, &Path> = HashMap::with_capacity(jobs.len());
+    for job in jobs {
+        if let Some(other) = outputs.insert(&job.exr, &job.raw) {
+            return Err(Raw2ExrError::InvalidInput(format!(
+                "{} and {} would both be written to {}, use {{dir}} in the name template",
+                other.display(),
+                job.raw.display(),
+                job.exr.display()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Collect files with a raw extension in a directory
+fn list_raws(dir: &Path, recursive: bool, files: &mut Vec<PathBuf>) -> Result<()> {
+    let io_error = |source| Raw2ExrError::Io {
+        path: dir.to_path_buf(),
+        source,
+    };
+
+    for entry in fs::read_dir(dir).map_err(io_error)? {
+        let path = entry.map_err(io_error)?.path();
+        if path.is_dir() {
+            if recursive {
+                list_raws(&path, recursive, files)?;
+            }
+        } else if is_raw(&path) {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+fn is_raw(path: &Path) -> bool {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .is_some_and(|extension| RAW_EXTENSIONS.contains(&extension.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(raw: &str, exr: &str) -> Job {
+        Job {
+            raw: raw.into(),
+            exr: exr.into(),
+        }
+    }
+
+    #[test]
+    fn dir_is_relative_to_the_output_dir() {
+        let batch = Batch {
+            output_dir: Some("out".into()),
+            template: "{dir}/{stem}.exr".to_string(),
+            ..Batch::default()
+        };
+        let job = batch.job("shoot/a/IMG_1.CR2".into(), Path::new("a"));
+        assert_eq!(job.exr, Path::new("out/a/IMG_1.exr"));
+    }
+
+    #[test]
+    fn dir_is_not_repeated_next_to_the_input() {
+        let batch = Batch {
+            template: "{dir}/{stem}.exr".to_string(),
+            ..Batch::default()
+        };
+        let job = batch.job("shoot/a/IMG_1.CR2".into(), Path::new("a"));
+        assert_eq!(job.exr, Path::new("shoot/a/IMG_1.exr"));
+    }
+
+    #[test]
+    fn same_output_twice_is_an_error() {
+        let jobs = [
+            job("shoot/a/IMG_1.CR2", "out/IMG_1.exr"),
+            job("shoot/b/IMG_1.CR2", "out/IMG_1.exr"),
+        ];
+        assert!(matches!(
+            check_outputs(&jobs),
+            Err(Raw2ExrError::InvalidInput(_))
+        ));
+        assert!(check_outputs(&jobs[..1]).is_ok());
+    }
+
+    #[test]
+    fn combined_jobs_may_share_an_output() {
+        let dir = std::env::temp_dir().join(format!("raw2exr-combined-{}", std::process::id()));
+        for subdir in ["a", "b"] {
+            fs::create_dir_all(dir.join(subdir)).unwrap();
+            fs::write(dir.join(subdir).join("IMG_1.CR2"), []).unwrap();
+        }
+        let mut batch = Batch {
+            output_dir: Some("out".into()),
+            recursive: true,
+            ..Batch::default()
+        };
+        let separate = batch.jobs(std::slice::from_ref(&dir));
+        batch.combined = true;
+        let combined = batch.jobs(std::slice::from_ref(&dir));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(separate, Err(Raw2ExrError::InvalidInput(_))));
+        assert_eq!(combined.unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,141 @@
+use rawloader::CFA;
+use rayon::prelude::*;
+
+/// Sensor data with a single sample per photosite, laid out following a CFA pattern
+#[derive(Debug, Clone)]
+pub struct Mosaic {
+    pub width: usize,
+    pub height: usize,
+    pub cfa: CFA,
+    pub data: Vec<f32>,
+}
+
+impl Mosaic {
+    /// Sample at given position
+    pub fn at(&self, x: usize, y: usize) -> f32 {
+        self.data[self.width * y + x]
+    }
+
+    /// CFA color index (0 = Red, 1 = Green, 2 = Blue) of the photosite at given position
+    pub fn color_at(&self, x: usize, y: usize) -> usize {
+        self.cfa.color_at(y, x)
+    }
+
+    /// Colors of one CFA period, row by row, as the letters rawloader builds patterns from
+    pub fn pattern(&self) -> String {
+        let mut pattern = String::with_capacity(self.cfa.width * self.cfa.height);
+        for y in 0..self.cfa.height {
+            for x in 0..self.cfa.width {
+                pattern.push(match self.color_at(x, y) {
+                    0 => 'R',
+                    1 => 'G',
+                    2 => 'B',
+                    _ => 'E',
+                });
+            }
+        }
+        pattern
+    }
+
+    /// Part of the mosaic starting at (left, top), with the CFA pattern shifted to match
+    pub fn crop(&self, left: usize, top: usize, width: usize, height: usize) -> Mosaic {
+        Mosaic {
+            width,
+            height,
+            cfa: self.cfa.shift(left, top),
+            data: plane_from_fn(width, height, |x, y| self.at(left + x, top + y)),
+        }
+    }
+}
+
+/// CFA of a pattern of given size, written row by row, when rawloader builds it unambiguously from its letters: only squares of 2, 6 or 12 photosites a side. rawloader panics on the others.
+pub fn square_cfa(pattern: &str, width: usize, height: usize) -> Option<CFA> {
+    let supported = (width == height)
+        && matches!(width, 2 | 6 | 12)
+        && (pattern.len() == width * height)
+        && pattern.chars().all(|color| "RGBE".contains(color));
+    supported.then(|| CFA::new(pattern))
+}
+
+/// Linear RGB image, stored as one plane per channel
+#[derive(Debug, Clone)]
+pub struct RgbImage {
+    pub width: usize,
+    pub height: usize,
+    pub red: Vec<f32>,
+    pub green: Vec<f32>,
+    pub blue: Vec<f32>,
+}
+
+impl RgbImage {
+    /// Black image of given size
+    pub fn new(width: usize, height: usize) -> RgbImage {
+        RgbImage {
+            width,
+            height,
+            red: vec![0.0; width * height],
+            green: vec![0.0; width * height],
+            blue: vec![0.0; width * height],
+        }
+    }
+
+    /// Image where every pixel is computed by `f` from its position, rows in parallel
+    pub fn from_fn(
+        width: usize,
+        height: usize,
+        f: impl Fn(usize, usize) -> (f32, f32, f32) + Sync,
+    ) -> RgbImage {
+        let mut image = RgbImage::new(width, height);
+        let row = width.max(1);
+        image
+            .red
+            .par_chunks_mut(row)
+            .zip(image.green.par_chunks_mut(row))
+            .zip(image.blue.par_chunks_mut(row))
+            .enumerate()
+            .for_each(|(y, ((red, green), blue))| {
+                let pixels = red.iter_mut().zip(green.iter_mut()).zip(blue.iter_mut());
+                for (x, ((r, g), b)) in pixels.enumerate() {
+                    (*r, *g, *b) = f(x, y);
+                }
+            });
+        image
+    }
+
+    /// RGB triplet at given position
+    pub fn pixel(&self, x: usize, y: usize) -> (f32, f32, f32) {
+        let index = self.width * y + x;
+        (self.red[index], self.green[index], self.blue[index])
+    }
+
+    /// Overwrite RGB triplet at given position
+    pub fn set_pixel(&mut self, x: usize, y: usize, (r, g, b): (f32, f32, f32)) {
+        let index = self.width * y + x;
+        self.red[index] = r;
+        self.green[index] = g;
+        self.blue[index] = b;
+    }
+
+    /// Part of the image starting at (left, top)
+    pub fn crop(&self, left: usize, top: usize, width: usize, height: usize) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| self.pixel(left + x, top + y))
+    }
+}
+
+/// Plane where every value is computed by `f` from its position, rows in parallel
+pub fn plane_from_fn<T: Copy + Default + Send>(
+    width: usize,
+    height: usize,
+    f: impl Fn(usize, usize) -> T + Sync,
+) -> Vec<T> {
+    let mut plane = vec![T::default(); width * height];
+    plane
+        .par_chunks_mut(width.max(1))
+        .enumerate()
+        .for_each(|(y, row)| {
+            for (x, value) in row.iter_mut().enumerate() {
+                *value = f(x, y);
+            }
+        });
+    plane
+}
@@ -0,0 +1,291 @@
+use std::path::Path;
+
+use exr::{
+    math::Vec2,
+    meta::attribute::{AttributeValue, Text},
+    prelude::{read, ReadChannels, ReadLayers},
+};
+use rayon::prelude::*;
+
+use crate::{
+    buffer::{square_cfa, Mosaic},
+    decode::Decoder,
+    encode::{MOSAIC_CHANNEL, MOSAIC_LAYER},
+    error::{Raw2ExrError, Result},
+    normalize::{Normalized, Normalizer},
+};
+
+/// Flat field values under this are left uncorrected
+const MIN_FLAT: f32 = 1e-4;
+
+/// Dark frame subtraction and flat field correction of normalized frames
+#[derive(Debug, Clone, Default)]
+pub struct Calibrator {
+    /// Subtracted from every frame
+    pub dark: Option<Normalized>,
+    /// Divides every frame, normalized so that each CFA channel averages 1.0
+    pub flat: Option<Normalized>,
+}
+
+impl Calibrator {
+    /// Read the dark and flat frames that are given, as camera raw files or master EXRs, normalizing the flat
+    pub fn from_files(
+        decoder: &Decoder,
+        normalizer: &Normalizer,
+        dark: Option<&Path>,
+        flat: Option<&Path>,
+    ) -> Result<Calibrator> {
+        let load = |path: &Path| {
+            let is_exr = path
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("exr"));
+            if is_exr {
+                read_master(path)
+            } else {
+                normalizer.normalize_any(&decoder.decode(path)?, decoder.level_order(path))
+            }
+        };
+        Ok(Calibrator {
+            dark: dark.map(load).transpose()?,
+            flat: flat.map(load).transpose()?.map(normalize_flat),
+        })
+    }
+
+    /// Subtract the dark frame, then divide by the flat field
+    pub fn apply(&self, frame: &mut Normalized) -> Result<()> {
+        for (calibration, name) in [(&self.dark, "dark frame"), (&self.flat, "flat field")] {
+            if calibration
+                .as_ref()
+                .is_some_and(|calibration| !calibration.same_layout(frame))
+            {
+                return Err(Raw2ExrError::UnsupportedLayout(format!(
+                    "{name} doesn't have the size and CFA pattern of the image"
+                )));
+            }
+        }
+
+        if let Some(dark) = &self.dark {
+            for (plane, dark) in frame.planes_mut().into_iter().zip(dark.planes()) {
+                plane
+                    .par_iter_mut()
+                    .zip(dark)
+                    .for_each(|(value, dark)| *value -= dark);
+            }
+        }
+        if let Some(flat) = &self.flat {
+            for (plane, flat) in frame.planes_mut().into_iter().zip(flat.planes()) {
+                plane.par_iter_mut().zip(flat).for_each(|(value, &flat)| {
+                    if flat > MIN_FLAT {
+                        *value /= flat;
+                    }
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read the "CFA Mosaic" layer of an EXR file, such as one written with `--stack --mosaic-layer --crop none --orientation keep`
+pub fn read_master(path: &Path) -> Result<Normalized> {
+    let decode_error = |message: String| Raw2ExrError::Decode {
+        path: path.to_path_buf(),
+        message,
+    };
+    let image = read()
+        .no_deep_data()
+        .largest_resolution_level()
+        .all_channels()
+        .all_layers()
+        .all_attributes()
+        .from_file(path)
+        .map_err(|e| decode_error(e.to_string()))?;
+
+    let layer = image
+        .layer_data
+        .iter()
+        .find(|layer| layer.attributes.layer_name == Some(Text::from(MOSAIC_LAYER)))
+        .ok_or_else(|| decode_error(format!("no \"{MOSAIC_LAYER}\" layer")))?;
+    let channel = layer
+        .channel_data
+        .list
+        .iter()
+        .find(|channel| channel.name.eq(MOSAIC_CHANNEL))
+        .ok_or_else(|| decode_error(format!("no {MOSAIC_CHANNEL} channel in the mosaic layer")))?;
+
+    let attribute = |name: &str| layer.attributes.other.get(&Text::from(name));
+    let (Some(AttributeValue::Text(pattern)), Some(&AttributeValue::IntVec2(Vec2(width, height)))) = (
+        attribute("raw2exr/cfaPattern"),
+        attribute("raw2exr/cfaSize"),
+    ) else {
+        return Err(decode_error(
+            "no CFA pattern in the mosaic layer".to_string(),
+        ));
+    };
+
+    let pattern = pattern.to_string();
+    let cfa = square_cfa(&pattern, width as usize, height as usize).ok_or_else(|| {
+        Raw2ExrError::UnsupportedLayout(format!(
+            "{width}x{height} CFA pattern {pattern} of {}",
+            path.display()
+        ))
+    })?;
+
+    Ok(Normalized::Mosaic(Box::new(Mosaic {
+        width: layer.size.0,
+        height: layer.size.1,
+        cfa,
+        data: channel.sample_data.values_as_f32().collect(),
+    })))
+}
+
+/// Scale a flat field so that every CFA channel, or every plane of a linear image, averages 1.0
+pub fn normalize_flat(flat: Normalized) -> Normalized {
+    match flat {
+        Normalized::Mosaic(mut mosaic) => {
+            let channels: Vec<usize> = (0..mosaic.data.len())
+                .map(|index| mosaic.color_at(index % mosaic.width, index / mosaic.width))
+                .collect();
+            let mut sums = [0.0f64; 4];
+            let mut counts = [0usize; 4];
+            for (&value, &channel) in mosaic.data.iter().zip(&channels) {
+                sums[channel] += value as f64;
+                counts[channel] += 1;
+            }
+            let means = [0, 1, 2, 3].map(|c| (sums[c] / counts[c].max(1) as f64) as f32);
+            mosaic
+                .data
+                .par_iter_mut()
+                .zip(channels)
+                .for_each(|(value, channel)| {
+                    if means[channel] > 0.0 {
+                        *value /= means[channel];
+                    }
+                });
+            Normalized::Mosaic(mosaic)
+        }
+        Normalized::Linear(mut image) => {
+            for plane in [&mut image.red, &mut image.green, &mut image.blue] {
+                let mean = plane.iter().map(|&value| value as f64).sum::<f64>()
+                    / plane.len().max(1) as f64;
+                if mean > 0.0 {
+                    plane.par_iter_mut().for_each(|value| *value /= mean as f32);
+                }
+            }
+            Normalized::Linear(image)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use exr::meta::attribute::Chromaticities;
+
+    use super::*;
+    use crate::{
+        buffer::RgbImage,
+        demosaic::tests::mosaic,
+        encode::{CropMode, ExrEncoder, ExtraLayers},
+        metadata::Metadata,
+    };
+
+    /// RGGB mosaic of a flat scene of given color
+    fn flat(width: usize, height: usize, [r, g, b]: [f32; 3]) -> Normalized {
+        Normalized::Mosaic(Box::new(mosaic("RGGB", width, height, |_, _| [r, g, b])))
+    }
+
+    fn samples(frame: &Normalized) -> Vec<f32> {
+        frame.planes()[0].to_vec()
+    }
+
+    #[test]
+    fn flat_channels_average_one() {
+        // Colored light, with vignetting on the left half
+        let field = mosaic("RGGB", 4, 4, |x, _| {
+            let light = if x < 2 { 0.5 } else { 1.0 };
+            [0.5 * light, 0.8 * light, 0.25 * light]
+        });
+        let normalized = normalize_flat(Normalized::Mosaic(Box::new(field)));
+        for (index, value) in samples(&normalized).into_iter().enumerate() {
+            let expected = if index % 4 < 2 { 2.0 / 3.0 } else { 4.0 / 3.0 };
+            assert!((value - expected).abs() < 1e-6, "{index}: {value}");
+        }
+    }
+
+    #[test]
+    fn dark_is_subtracted_before_dividing_by_the_flat() {
+        let calibrator = Calibrator {
+            dark: Some(flat(2, 2, [0.1; 3])),
+            flat: Some(flat(2, 2, [2.0, 0.5, 0.0])),
+        };
+        let mut frame = flat(2, 2, [0.5; 3]);
+        calibrator.apply(&mut frame).unwrap();
+        // Blue is left alone where the flat field has no light
+        assert_eq!(samples(&frame), vec![0.2, 0.8, 0.8, 0.4]);
+    }
+
+    #[test]
+    fn calibration_frames_must_match_the_layout() {
+        let shifted = Normalized::Mosaic(Box::new(mosaic("GRBG", 2, 2, |_, _| [0.0; 3])));
+        for calibrator in [
+            Calibrator {
+                dark: Some(flat(4, 2, [0.0; 3])),
+                flat: None,
+            },
+            Calibrator {
+                dark: None,
+                flat: Some(flat(2, 4, [1.0; 3])),
+            },
+            Calibrator {
+                dark: Some(shifted),
+                flat: None,
+            },
+        ] {
+            assert!(matches!(
+                calibrator.apply(&mut flat(2, 2, [0.5; 3])),
+                Err(Raw2ExrError::UnsupportedLayout(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn masters_round_trip_through_exr() {
+        let master = mosaic("GBRG", 6, 4, |x, y| [(6 * y + x) as f32 + 0.5; 3]);
+        let encoder = ExrEncoder {
+            crop: CropMode::None,
+            mosaic_layer: true,
+            ..ExrEncoder::default()
+        };
+        let path = std::env::temp_dir().join(format!("raw2exr-master-{}.exr", std::process::id()));
+        let chromaticities = Chromaticities {
+            red: Vec2(0.64, 0.33),
+            green: Vec2(0.3, 0.6),
+            blue: Vec2(0.15, 0.06),
+            white: Vec2(0.3127, 0.329),
+        };
+        encoder
+            .encode(
+                &RgbImage::new(6, 4),
+                ExtraLayers {
+                    mosaic: Some(&master),
+                    mask: None,
+                },
+                [0; 4],
+                chromaticities,
+                &Metadata::default(),
+                &path,
+            )
+            .unwrap();
+        let calibrator =
+            Calibrator::from_files(&Decoder, &Normalizer::default(), Some(&path), Some(&path));
+        std::fs::remove_file(&path).unwrap();
+
+        let calibrator = calibrator.unwrap();
+        let master = Normalized::Mosaic(Box::new(master));
+        let dark = calibrator.dark.unwrap();
+        assert!(dark.same_layout(&master));
+        assert_eq!(samples(&dark), samples(&master));
+        let flat = calibrator.flat.unwrap();
+        assert!(flat.same_layout(&master));
+        assert_eq!(samples(&flat), samples(&normalize_flat(master)));
+    }
+}
@@ -0,0 +1,178 @@
+pub mod primaries;
+pub mod spaces;
+
+use exr::{math::Vec2, meta::attribute::Chromaticities};
+use nalgebra::SMatrix;
+use rawloader::RawImage;
+use rayon::prelude::*;
+
+use crate::{buffer::RgbImage, error::Result};
+
+pub use primaries::{camera_primaries, white_xyz, PrimariesError};
+pub use spaces::{OutputSpace, Primaries};
+
+pub type Matrix3x3f = SMatrix<f32, 3, 3>;
+pub type Matrix3x1f = SMatrix<f32, 3, 1>;
+
+/// Bradford cone response matrix, used for chromatic adaptation
+#[rustfmt::skip]
+fn bradford_cone_response() -> Matrix3x3f {
+    Matrix3x3f::new(
+        0.8951, 0.2664, -0.1614,
+        -0.7502, 1.7135, 0.0367,
+        0.0389, -0.0685, 1.0296,
+    )
+}
+
+/// Converts white-balanced camera RGB to the output color space, and describes the result
+#[derive(Debug, Clone, Default)]
+pub struct ColorTransform {
+    pub output: OutputSpace,
+}
+
+impl ColorTransform {
+    /// Why camera-native output falls back to XYZ, if it does: a camera primary has no chromaticity.
+    ///
+    /// Other matrix errors also break the conversion to XYZ, so they are returned as errors instead.
+    pub fn fallback(
+        &self,
+        image: &RawImage,
+        white_balance: [f32; 3],
+    ) -> Result<Option<PrimariesError>> {
+        if self.output != OutputSpace::Camera {
+            return Ok(None);
+        }
+        match camera_primaries(&cam_to_xyz(image), white_balance) {
+            Ok(_) => Ok(None),
+            Err(e @ PrimariesError::Degenerate(_)) => Ok(Some(e)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Output space actually used, see [`ColorTransform::fallback`]
+    pub fn effective_output(
+        &self,
+        image: &RawImage,
+        white_balance: [f32; 3],
+    ) -> Result<OutputSpace> {
+        Ok(match self.fallback(image, white_balance)? {
+            Some(_) => OutputSpace::Xyz,
+            None => self.output,
+        })
+    }
+
+    /// Chromaticities of the output pixels
+    pub fn chromaticities(
+        &self,
+        image: &RawImage,
+        white_balance: [f32; 3],
+    ) -> Result<Chromaticities> {
+        Ok(match self.effective_output(image, white_balance)? {
+            OutputSpace::Camera => camera_primaries(&cam_to_xyz(image), white_balance)?.into(),
+            OutputSpace::Xyz => {
+                let [x, y] = xyz_to_xy(white_xyz(&cam_to_xyz(image), white_balance)?);
+                Chromaticities {
+                    red: Vec2(1.0, 0.0),
+                    green: Vec2(0.0, 1.0),
+                    blue: Vec2(0.0, 0.0),
+                    white: Vec2(x, y),
+                }
+            }
+            space => space.primaries().unwrap().into(),
+        })
+    }
+
+    /// Matrix from white-balanced, normalized camera RGB to the output space, `None` if pixels stay as they are.
+    ///
+    /// The white picked by white balance maps to Y = 1, and to (1, 1, 1) in RGB spaces through a Bradford adaptation to the space's white.
+    pub fn matrix(&self, image: &RawImage, white_balance: [f32; 3]) -> Result<Option<Matrix3x3f>> {
+        let output = self.effective_output(image, white_balance)?;
+        if output == OutputSpace::Camera {
+            return Ok(None);
+        }
+
+        let cam_to_xyz = cam_to_xyz(image);
+        let source_white = white_xyz(&cam_to_xyz, white_balance)?;
+        let to_xyz = cam_to_xyz
+            * Matrix3x3f::from_diagonal(&Matrix3x1f::from(white_balance).map(|c| 1.0 / c))
+            / source_white.y;
+
+        Ok(match output {
+            OutputSpace::Xyz => Some(to_xyz),
+            space => {
+                let primaries = space.primaries().unwrap();
+                let rgb_to_xyz = rgb_to_xyz(&primaries);
+                let target_white = xy_to_xyz(primaries.white);
+                Some(
+                    rgb_to_xyz.try_inverse().unwrap()
+                        * bradford(source_white / source_white.y, target_white)
+                        * to_xyz,
+                )
+            }
+        })
+    }
+
+    /// Convert pixels to the output space
+    pub fn apply(
+        &self,
+        rgb: &mut RgbImage,
+        image: &RawImage,
+        white_balance: [f32; 3],
+    ) -> Result<()> {
+        let Some(matrix) = self.matrix(image, white_balance)? else {
+            return Ok(());
+        };
+
+        rgb.red
+            .par_iter_mut()
+            .zip(rgb.green.par_iter_mut())
+            .zip(rgb.blue.par_iter_mut())
+            .for_each(|((r, g), b)| {
+                let converted = matrix * Matrix3x1f::new(*r, *g, *b);
+                (*r, *g, *b) = (converted.x, converted.y, converted.z);
+            });
+
+        Ok(())
+    }
+}
+
+/// Camera RGB to XYZ matrix of given image
+pub fn cam_to_xyz(image: &RawImage) -> Matrix3x3f {
+    // Throwing out last component, don't know what it's for really
+    let m = image.cam_to_xyz();
+    Matrix3x3f::new(
+        m[0][0], m[0][1], m[0][2], m[1][0], m[1][1], m[1][2], m[2][0], m[2][1], m[2][2],
+    )
+}
+
+/// XYZ with Y = 1 of given xy chromaticity
+pub fn xy_to_xyz([x, y]: [f32; 2]) -> Matrix3x1f {
+    Matrix3x1f::new(x / y, 1.0, (1.0 - x - y) / y)
+}
+
+/// xy chromaticity of given XYZ
+pub fn xyz_to_xy(xyz: Matrix3x1f) -> [f32; 2] {
+    let sum = xyz.x + xyz.y + xyz.z;
+    [xyz.x / sum, xyz.y / sum]
+}
+
+/// Linear RGB to XYZ matrix of an RGB space, scaled so that RGB white has Y = 1
+pub fn rgb_to_xyz(primaries: &Primaries) -> Matrix3x3f {
+    let columns = Matrix3x3f::from_columns(&[
+        xy_to_xyz(primaries.red),
+        xy_to_xyz(primaries.green),
+        xy_to_xyz(primaries.blue),
+    ]);
+    let scales = columns.try_inverse().unwrap() * xy_to_xyz(primaries.white);
+    columns * Matrix3x3f::from_diagonal(&scales)
+}
+
+/// Bradford chromatic adaptation from one XYZ white to another
+pub fn bradford(source_white: Matrix3x1f, target_white: Matrix3x1f) -> Matrix3x3f {
+    let cone_response = bradford_cone_response();
+    let source_cone = cone_response * source_white;
+    let target_cone = cone_response * target_white;
+    cone_response.try_inverse().unwrap()
+        * Matrix3x3f::from_diagonal(&target_cone.component_div(&source_cone))
+        * cone_response
+}
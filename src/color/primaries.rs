@@ -0,0 +1,151 @@
+use std::{error::Error, fmt};
+
+use super::{spaces::Primaries, xyz_to_xy, Matrix3x1f, Matrix3x3f};
+
+/// Below this, X + Y + Z is considered zero and the chromaticity undefined
+const EPSILON: f32 = 1e-6;
+
+/// Why camera primaries could not be derived from a color matrix
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PrimariesError {
+    /// The matrix contains NaN or infinite values, usually because the camera has no known matrix
+    NonFinite,
+    /// A primary has X + Y + Z of zero, so it has no chromaticity
+    Degenerate(&'static str),
+    /// White has a luminance of zero or below
+    WhiteNotPositive(f32),
+}
+
+impl fmt::Display for PrimariesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrimariesError::NonFinite => write!(f, "camera color matrix is not finite"),
+            PrimariesError::Degenerate(channel) => {
+                write!(f, "camera {channel} primary has no chromaticity")
+            }
+            PrimariesError::WhiteNotPositive(y) => {
+                write!(f, "camera white has a non-positive luminance ({y})")
+            }
+        }
+    }
+}
+
+impl Error for PrimariesError {}
+
+/// Chromaticities of the camera RGB space, read from the columns of its camera to XYZ matrix.
+///
+/// Each column is the XYZ of a camera primary. Cameras often have imaginary primaries outside the spectral locus, with negative X, Y or Z, which is fine as long as X + Y + Z is not zero: xy is the same for a column and its opposite. White is the camera color made neutral by the given white balance coefficients.
+pub fn camera_primaries(
+    cam_to_xyz: &Matrix3x3f,
+    white_balance: [f32; 3],
+) -> Result<Primaries, PrimariesError> {
+    let white = white_xyz(cam_to_xyz, white_balance)?;
+
+    let chromaticity = |xyz: Matrix3x1f, name: &'static str| {
+        if (xyz.x + xyz.y + xyz.z).abs() < EPSILON {
+            Err(PrimariesError::Degenerate(name))
+        } else {
+            Ok(xyz_to_xy(xyz))
+        }
+    };
+
+    Ok(Primaries {
+        red: chromaticity(cam_to_xyz.column(0).into_owned(), "red")?,
+        green: chromaticity(cam_to_xyz.column(1).into_owned(), "green")?,
+        blue: chromaticity(cam_to_xyz.column(2).into_owned(), "blue")?,
+        white: chromaticity(white, "white")?,
+    })
+}
+
+/// XYZ of the camera color that white balance makes neutral
+pub fn white_xyz(
+    cam_to_xyz: &Matrix3x3f,
+    white_balance: [f32; 3],
+) -> Result<Matrix3x1f, PrimariesError> {
+    if cam_to_xyz.iter().any(|value| !value.is_finite()) {
+        return Err(PrimariesError::NonFinite);
+    }
+
+    let white = cam_to_xyz * Matrix3x1f::from(white_balance).map(|c| 1.0 / c);
+    if white.y <= 0.0 {
+        return Err(PrimariesError::WhiteNotPositive(white.y));
+    }
+    Ok(white)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Nikon D750 XYZ to camera matrix, from rawloader's camera definitions
+    #[rustfmt::skip]
+    fn d750_cam_to_xyz() -> Matrix3x3f {
+        Matrix3x3f::new(
+            0.9020, -0.2890, -0.0715,
+            -0.4535, 1.2436, 0.2348,
+            -0.0934, 0.1919, 0.7086,
+        )
+        .try_inverse()
+        .unwrap()
+    }
+
+    /// White balance that makes the camera response to D65 neutral
+    fn d65_white_balance(cam_to_xyz: &Matrix3x3f) -> [f32; 3] {
+        let d65 = Matrix3x1f::new(0.950_47, 1.0, 1.088_83);
+        let camera = cam_to_xyz.try_inverse().unwrap() * d65;
+        [camera.y / camera.x, 1.0, camera.y / camera.z]
+    }
+
+    #[test]
+    fn real_camera_primaries() {
+        let cam_to_xyz = d750_cam_to_xyz();
+        let primaries = camera_primaries(&cam_to_xyz, d65_white_balance(&cam_to_xyz)).unwrap();
+
+        let [x, y] = primaries.white;
+        assert!((x - 0.3127).abs() < 1e-3, "white x {x}");
+        assert!((y - 0.3290).abs() < 1e-3, "white y {y}");
+        // Primaries sit roughly where red, green and blue are expected
+        assert!(primaries.red[0] > primaries.green[0]);
+        assert!(primaries.green[1] > primaries.blue[1]);
+        assert!(primaries.blue[0] < primaries.red[0]);
+    }
+
+    #[test]
+    fn negative_primary_has_the_chromaticity_of_its_opposite() {
+        #[rustfmt::skip]
+        let cam_to_xyz = Matrix3x3f::new(
+            0.6, -0.3, 0.2,
+            0.3, -0.6, 0.1,
+            0.1, -0.1, 0.9,
+        );
+        let primaries = camera_primaries(&cam_to_xyz, [1.0, 1.0, 1.0]);
+        // White has a negative luminance with these columns
+        assert!(matches!(primaries, Err(PrimariesError::WhiteNotPositive(y)) if y < 0.0));
+
+        let primaries = camera_primaries(&cam_to_xyz, [1.0, -1.0, 1.0]).unwrap();
+        assert_eq!(primaries.green, xyz_to_xy(Matrix3x1f::new(0.3, 0.6, 0.1)));
+    }
+
+    #[test]
+    fn degenerate_primary() {
+        #[rustfmt::skip]
+        let cam_to_xyz = Matrix3x3f::new(
+            0.6, 0.5, 0.2,
+            0.3, -0.5, 0.8,
+            0.1, 0.0, 0.9,
+        );
+        assert_eq!(
+            camera_primaries(&cam_to_xyz, [1.0, 1.0, 1.0]),
+            Err(PrimariesError::Degenerate("green"))
+        );
+    }
+
+    #[test]
+    fn unknown_matrix() {
+        let cam_to_xyz = Matrix3x3f::from_element(f32::NAN);
+        assert_eq!(
+            camera_primaries(&cam_to_xyz, [1.0, 1.0, 1.0]),
+            Err(PrimariesError::NonFinite)
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use clap::ValueEnum;
+use exr::{math::Vec2, meta::attribute::Chromaticities};
+
+/// CIE xy coordinates of the three primaries and white point of an RGB space
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Primaries {
+    pub red: [f32; 2],
+    pub green: [f32; 2],
+    pub blue: [f32; 2],
+    pub white: [f32; 2],
+}
+
+impl From<Primaries> for Chromaticities {
+    fn from(primaries: Primaries) -> Self {
+        let xy = |[x, y]: [f32; 2]| Vec2(x, y);
+        Chromaticities {
+            red: xy(primaries.red),
+            green: xy(primaries.green),
+            blue: xy(primaries.blue),
+            white: xy(primaries.white),
+        }
+    }
+}
+
+pub const D65_WHITE: [f32; 2] = [0.3127, 0.3290];
+pub const ACES_WHITE: [f32; 2] = [0.32168, 0.33767];
+
+/// Color space of the pixels written in the EXR file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputSpace {
+    /// Camera-native primaries, only described by the chromaticities attribute
+    #[default]
+    Camera,
+    /// ACES2065-1 (AP0 primaries, ACES white)
+    #[value(name = "aces2065-1")]
+    Aces2065,
+    /// ACEScg (AP1 primaries, ACES white)
+    #[value(name = "acescg")]
+    AcesCg,
+    /// Linear Rec.709 / sRGB
+    #[value(name = "rec709", alias = "srgb")]
+    Rec709,
+    /// Linear Rec.2020
+    #[value(name = "rec2020")]
+    Rec2020,
+    /// Linear Display P3
+    #[value(name = "display-p3")]
+    DisplayP3,
+    /// CIE XYZ, without chromatic adaptation
+    #[value(name = "xyz")]
+    Xyz,
+}
+
+impl OutputSpace {
+    /// Primaries of RGB working spaces, `None` for camera-native and XYZ output
+    pub fn primaries(self) -> Option<Primaries> {
+        match self {
+            OutputSpace::Camera | OutputSpace::Xyz => None,
+            OutputSpace::Aces2065 => Some(Primaries {
+                red: [0.7347, 0.2653],
+                green: [0.0, 1.0],
+                blue: [0.0001, -0.0770],
+                white: ACES_WHITE,
+            }),
+            OutputSpace::AcesCg => Some(Primaries {
+                red: [0.713, 0.293],
+                green: [0.165, 0.830],
+                blue: [0.128, 0.044],
+                white: ACES_WHITE,
+            }),
+            OutputSpace::Rec709 => Some(Primaries {
+                red: [0.64, 0.33],
+                green: [0.30, 0.60],
+                blue: [0.15, 0.06],
+                white: D65_WHITE,
+            }),
+            OutputSpace::Rec2020 => Some(Primaries {
+                red: [0.708, 0.292],
+                green: [0.170, 0.797],
+                blue: [0.131, 0.046],
+                white: D65_WHITE,
+            }),
+            OutputSpace::DisplayP3 => Some(Primaries {
+                red: [0.680, 0.320],
+                green: [0.265, 0.690],
+                blue: [0.150, 0.060],
+                white: D65_WHITE,
+            }),
+        }
+    }
+}
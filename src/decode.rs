@@ -0,0 +1,164 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+use rawloader::RawImage;
+
+use crate::{
+    error::{Raw2ExrError, Result},
+    normalize::LevelOrder,
+};
+
+/// Bytes read to find the first IFD of a TIFF based file
+const HEADER_SIZE: u64 = 64 * 1024;
+
+/// TIFF tags rawloader picks its DNG and PEF decoders by
+const DNG_VERSION_TAG: u32 = 0xc612;
+const MAKE_TAG: u32 = 0x010f;
+
+/// Camera makes rawloader reads with its PEF decoder
+const PEF_MAKES: [&str; 3] = [
+    "PENTAX Corporation",
+    "RICOH IMAGING COMPANY, LTD.",
+    "PENTAX",
+];
+
+/// First stage, reads a camera raw file from disk
+#[derive(Debug, Clone, Default)]
+pub struct Decoder;
+
+impl Decoder {
+    pub fn decode(&self, path: &Path) -> Result<RawImage> {
+        let file = File::open(path).map_err(|source| Raw2ExrError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        rawloader::decode(&mut BufReader::new(file)).map_err(|e| Raw2ExrError::Decode {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        })
+    }
+
+    /// Order rawloader leaves black and white levels in for a file, which keeps them as stored for DNG and PEF. Those are told apart by their TIFF header, as rawloader does.
+    pub fn level_order(&self, path: &Path) -> LevelOrder {
+        let mut header = Vec::new();
+        let read =
+            File::open(path).and_then(|file| file.take(HEADER_SIZE).read_to_end(&mut header));
+        if read.is_ok() && is_dng_or_pef(&header).unwrap_or(false) {
+            LevelOrder::Position
+        } else {
+            LevelOrder::Color
+        }
+    }
+}
+
+/// Whether the first IFD of a TIFF header has the DNG version tag or a PEF camera make, `None` if it can't be read
+fn is_dng_or_pef(header: &[u8]) -> Option<bool> {
+    let big_endian = match header.get(..4)? {
+        b"II*\0" => false,
+        b"MM\0*" => true,
+        _ => return None,
+    };
+    // Unsigned integer of `size` bytes at `offset`
+    let read = |offset: usize, size: usize| {
+        let bytes = header.get(offset..offset.checked_add(size)?)?;
+        let value = |value: u32, &byte: &u8| (value << 8) | u32::from(byte);
+        Some(if big_endian {
+            bytes.iter().fold(0, value)
+        } else {
+            bytes.iter().rev().fold(0, value)
+        })
+    };
+
+    let ifd = read(4, 4)? as usize;
+    for index in 0..read(ifd, 2)? as usize {
+        let entry = ifd + 2 + 12 * index;
+        match read(entry, 2)? {
+            DNG_VERSION_TAG => return Some(true),
+            MAKE_TAG => {
+                // ASCII, stored in the entry when it fits in 4 bytes
+                let length = read(entry + 4, 4)? as usize;
+                let start = if length > 4 {
+                    read(entry + 8, 4)? as usize
+                } else {
+                    entry + 8
+                };
+                let make = header.get(start..start.checked_add(length)?)?;
+                let make = make.split(|&byte| byte == 0).next().unwrap_or_default();
+                let make = String::from_utf8_lossy(make);
+                if PEF_MAKES.contains(&make.trim()) {
+                    return Some(true);
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// TIFF header with a first IFD holding a single ASCII or byte entry
+    fn tiff(big_endian: bool, tag: u16, kind: u16, value: &[u8]) -> Vec<u8> {
+        let u16_bytes = |value: u16| {
+            if big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            }
+        };
+        let u32_bytes = |value: u32| {
+            if big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            }
+        };
+
+        let mut header = if big_endian {
+            b"MM\0*".to_vec()
+        } else {
+            b"II*\0".to_vec()
+        };
+        header.extend(u32_bytes(8));
+        header.extend(u16_bytes(1));
+        header.extend(u16_bytes(tag));
+        header.extend(u16_bytes(kind));
+        header.extend(u32_bytes(value.len() as u32));
+        if value.len() > 4 {
+            // After the entry and the offset of the next IFD
+            header.extend(u32_bytes(8 + 2 + 12 + 4));
+            header.extend(u32_bytes(0));
+            header.extend(value);
+        } else {
+            let mut inline = value.to_vec();
+            inline.resize(4, 0);
+            header.extend(inline);
+            header.extend(u32_bytes(0));
+        }
+        header
+    }
+
+    #[test]
+    fn dng_and_pef_are_found_from_the_header() {
+        for big_endian in [false, true] {
+            let dng = tiff(big_endian, 0xc612, 1, &[1, 4, 0, 0]);
+            assert_eq!(is_dng_or_pef(&dng), Some(true));
+            let pef = tiff(big_endian, 0x010f, 2, b"PENTAX Corporation \0");
+            assert_eq!(is_dng_or_pef(&pef), Some(true));
+            let cr2 = tiff(big_endian, 0x010f, 2, b"Canon\0");
+            assert_eq!(is_dng_or_pef(&cr2), Some(false));
+        }
+    }
+
+    #[test]
+    fn other_headers_are_not_dng_or_pef() {
+        assert_eq!(is_dng_or_pef(b"FUJIFILMCCD-RAW"), None);
+        assert_eq!(is_dng_or_pef(b"II*\0\xff\xff\0\0"), None);
+        assert_eq!(is_dng_or_pef(&[]), None);
+    }
+}
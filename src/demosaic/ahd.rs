@@ -0,0 +1,170 @@
+use crate::buffer::{plane_from_fn, Mosaic, RgbImage};
+
+use super::{is_bayer, limit, mirror, to_lab, Bilinear, Demosaic, Planes};
+
+/// Adaptive Homogeneity-Directed demosaicing (Hirakawa and Parks).
+///
+/// The image is interpolated both horizontally and vertically, then each pixel picks the candidate that is most homogeneous with its neighbours in CIELab. Falls back to [`Bilinear`] for non-Bayer patterns.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Ahd;
+
+/// Horizontal and vertical unit steps
+const DIRECTIONS: [(isize, isize); 2] = [(1, 0), (0, 1)];
+
+/// Left, right, up and down neighbours
+const NEIGHBOURS: [(isize, isize); 4] = [(-1, 0), (1, 0), (0, -1), (0, 1)];
+
+impl Demosaic for Ahd {
+    fn demosaic(&self, mosaic: &Mosaic) -> RgbImage {
+        if !is_bayer(&mosaic.cfa) {
+            return Bilinear.demosaic(mosaic);
+        }
+
+        let base = Planes::from_mosaic(mosaic);
+        let candidates = DIRECTIONS.map(|direction| interpolate_along(mosaic, &base, direction));
+        let labs = candidates.each_ref().map(to_lab);
+
+        let width = mosaic.width;
+        let height = mosaic.height;
+        let lab_at = |candidate: usize, x: isize, y: isize| {
+            labs[candidate][width * mirror(y, height) + mirror(x, width)]
+        };
+
+        // Count neighbours close enough in lightness and chroma, for each candidate
+        let homogeneity = plane_from_fn(width, height, |x, y| {
+            let (x, y) = (x as isize, y as isize);
+            let mut l_diffs = [[0.0f32; 4]; 2];
+            let mut ab_diffs = [[0.0f32; 4]; 2];
+            for (candidate, (l_diff, ab_diff)) in
+                l_diffs.iter_mut().zip(ab_diffs.iter_mut()).enumerate()
+            {
+                let center = lab_at(candidate, x, y);
+                for (i, (dx, dy)) in NEIGHBOURS.into_iter().enumerate() {
+                    let neighbour = lab_at(candidate, x + dx, y + dy);
+                    l_diff[i] = (center[0] - neighbour[0]).abs();
+                    ab_diff[i] =
+                        (center[1] - neighbour[1]).powi(2) + (center[2] - neighbour[2]).powi(2);
+                }
+            }
+
+            // Tolerances taken from the direction each candidate was interpolated along
+            let l_epsilon = l_diffs[0][0]
+                .max(l_diffs[0][1])
+                .min(l_diffs[1][2].max(l_diffs[1][3]));
+            let ab_epsilon = ab_diffs[0][0]
+                .max(ab_diffs[0][1])
+                .min(ab_diffs[1][2].max(ab_diffs[1][3]));
+
+            let mut counts = [0u8; 2];
+            for (candidate, count) in counts.iter_mut().enumerate() {
+                *count = (0..4)
+                    .filter(|&i| {
+                        (l_diffs[candidate][i] <= l_epsilon)
+                            & (ab_diffs[candidate][i] <= ab_epsilon)
+                    })
+                    .count() as u8;
+            }
+            counts
+        });
+
+        // Pick the most homogeneous candidate over a 3x3 window, or blend both on a tie
+        RgbImage::from_fn(width, height, |x, y| {
+            let mut scores = [0u32; 2];
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let ax = mirror(x as isize + dx, width);
+                    let ay = mirror(y as isize + dy, height);
+                    let counts = homogeneity[width * ay + ax];
+                    scores[0] += counts[0] as u32;
+                    scores[1] += counts[1] as u32;
+                }
+            }
+
+            let index = width * y + x;
+            let pixel = |candidate: usize| {
+                let [red, green, blue] = &candidates[candidate].planes;
+                (red[index], green[index], blue[index])
+            };
+            match scores[0].cmp(&scores[1]) {
+                std::cmp::Ordering::Greater => pixel(0),
+                std::cmp::Ordering::Less => pixel(1),
+                std::cmp::Ordering::Equal => {
+                    let (a, b) = (pixel(0), pixel(1));
+                    ((a.0 + b.0) / 2.0, (a.1 + b.1) / 2.0, (a.2 + b.2) / 2.0)
+                }
+            }
+        })
+    }
+}
+
+/// Interpolate green along a single direction, then red and blue from color differences against that green
+fn interpolate_along(mosaic: &Mosaic, base: &Planes, (dx, dy): (isize, isize)) -> Planes {
+    let (width, height) = (mosaic.width, mosaic.height);
+    let mut planes = base.clone();
+
+    planes.planes[1] = plane_from_fn(width, height, |x, y| {
+        let c = mosaic.color_at(x, y);
+        let (xi, yi) = (x as isize, y as isize);
+        if c == 1 {
+            return base.get(1, xi, yi);
+        }
+        let before = base.get(1, xi - dx, yi - dy);
+        let after = base.get(1, xi + dx, yi + dy);
+        let green = (before + after) / 2.0
+            + (2.0 * base.get(c, xi, yi)
+                - base.get(c, xi - 2 * dx, yi - 2 * dy)
+                - base.get(c, xi + 2 * dx, yi + 2 * dy))
+                / 4.0;
+        limit(green, before, after)
+    });
+
+    for color in [0, 2] {
+        let plane = plane_from_fn(width, height, |x, y| {
+            let (xi, yi) = (x as isize, y as isize);
+            if mosaic.color_at(x, y) == color {
+                return planes.get(color, xi, yi);
+            }
+
+            // Average color difference of same-colored photosites in the 3x3 window
+            let mut sum = 0.0;
+            let mut count = 0;
+            for ny in -1..=1 {
+                for nx in -1..=1 {
+                    let ax = mirror(xi + nx, width);
+                    let ay = mirror(yi + ny, height);
+                    if mosaic.color_at(ax, ay) != color {
+                        continue;
+                    }
+                    sum += planes.get(color, ax as isize, ay as isize)
+                        - planes.get(1, ax as isize, ay as isize);
+                    count += 1;
+                }
+            }
+
+            if count > 0 {
+                planes.get(1, xi, yi) + sum / count as f32
+            } else {
+                planes.get(color, xi, yi)
+            }
+        });
+        planes.planes[color] = plane;
+    }
+
+    planes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demosaic::tests::{assert_flat_field, assert_no_zipper, BAYER};
+
+    #[test]
+    fn flat_field() {
+        assert_flat_field(&Ahd);
+    }
+
+    #[test]
+    fn no_zipper() {
+        assert_no_zipper(&Ahd, &BAYER);
+    }
+}
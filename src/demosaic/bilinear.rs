@@ -0,0 +1,92 @@
+use crate::buffer::{Mosaic, RgbImage};
+
+use super::{mirror, Demosaic, RGB_COLORS};
+
+/// Fill each missing component with the distance-weighted average of the closest photosites of that color.
+///
+/// Works for any repeating CFA pattern: the search window grows until a photosite of the wanted color is found, which is one step for Bayer and can be more for larger patterns. Positions outside the image are mirrored back in.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bilinear;
+
+impl Demosaic for Bilinear {
+    fn demosaic(&self, mosaic: &Mosaic) -> RgbImage {
+        RgbImage::from_fn(mosaic.width, mosaic.height, |x, y| {
+            let own_color = mosaic.color_at(x, y);
+
+            let mut rgb = [0.0f32; 3];
+            for color in RGB_COLORS {
+                rgb[color] = if color == own_color {
+                    mosaic.at(x, y)
+                } else {
+                    interpolate(mosaic, x, y, color)
+                };
+            }
+
+            (rgb[0], rgb[1], rgb[2])
+        })
+    }
+}
+
+/// Distance-weighted average of the nearest photosites of given color around a position
+pub fn interpolate(mosaic: &Mosaic, x: usize, y: usize, color: usize) -> f32 {
+    let max_radius = mosaic.cfa.width.max(mosaic.cfa.height).max(1) as isize;
+
+    for radius in 1..=max_radius {
+        let mut sum = 0.0;
+        let mut weights = 0.0;
+
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                if (dx == 0) & (dy == 0) {
+                    continue;
+                }
+                let ax = mirror(x as isize + dx, mosaic.width);
+                let ay = mirror(y as isize + dy, mosaic.height);
+                if mosaic.color_at(ax, ay) != color {
+                    continue;
+                }
+                let weight = 1.0 / (dx * dx + dy * dy) as f32;
+                sum += mosaic.at(ax, ay) * weight;
+                weights += weight;
+            }
+        }
+
+        if weights > 0.0 {
+            return sum / weights;
+        }
+    }
+
+    // Color not present in the pattern at all
+    0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demosaic::tests::{assert_flat_field, assert_scene, mosaic, BAYER, X_TRANS};
+
+    #[test]
+    fn flat_field() {
+        assert_flat_field(&Bilinear);
+    }
+
+    #[test]
+    fn one_pixel_wide_x_trans_stays_flat() {
+        // A single X-Trans column or row still has every color
+        let grey = |_, _| [0.5; 3];
+        let column = Bilinear.demosaic(&mosaic(X_TRANS, 1, 12, grey));
+        assert_scene(&column, grey, "column");
+        let row = Bilinear.demosaic(&mosaic(X_TRANS, 12, 1, grey));
+        assert_scene(&row, grey, "row");
+    }
+
+    #[test]
+    fn one_pixel_wide_bayer_keeps_the_colors_it_has() {
+        // A single Bayer column only has two colors, the missing one can't be interpolated
+        let grey = |_, _| [0.5; 3];
+        let image = Bilinear.demosaic(&mosaic(BAYER[0], 1, 5, grey));
+        assert_scene(&image, |_, _| [0.5, 0.5, 0.0], BAYER[0]);
+        let image = Bilinear.demosaic(&mosaic(BAYER[3], 5, 1, grey));
+        assert_scene(&image, |_, _| [0.0, 0.5, 0.5], BAYER[3]);
+    }
+}
@@ -0,0 +1,261 @@
+use crate::buffer::{plane_from_fn, Mosaic, RgbImage};
+
+use super::{bilinear, limit, mirror, to_lab, Demosaic, Planes};
+
+/// Markesteijn-style demosaicing, designed for Fujifilm X-Trans but usable with any CFA.
+///
+/// Green is interpolated along four directions (horizontal, vertical and both diagonals), red and blue follow from color differences, and each pixel averages the candidates that are most homogeneous in CIELab. With more than one pass, a refined copy of each candidate re-estimates green from the color differences of the previous pass, doubling the number of candidates.
+#[derive(Debug, Clone, Copy)]
+pub struct Markesteijn {
+    pub passes: usize,
+}
+
+impl Default for Markesteijn {
+    fn default() -> Self {
+        Markesteijn { passes: 1 }
+    }
+}
+
+/// Horizontal, vertical and diagonal unit steps
+const DIRECTIONS: [(isize, isize); 4] = [(1, 0), (0, 1), (1, 1), (1, -1)];
+
+/// How far along a line a green photosite is looked for
+const REACH: isize = 3;
+
+impl Demosaic for Markesteijn {
+    fn demosaic(&self, mosaic: &Mosaic) -> RgbImage {
+        let base = Planes::from_mosaic(mosaic);
+
+        let mut candidates: Vec<(Planes, (isize, isize))> = DIRECTIONS
+            .iter()
+            .map(|&direction| {
+                let mut planes = green_along(mosaic, &base, direction);
+                fill_red_blue(mosaic, &mut planes);
+                (planes, direction)
+            })
+            .collect();
+
+        if self.passes > 1 {
+            let mut refined = candidates.clone();
+            for _ in 1..self.passes {
+                for (planes, direction) in refined.iter_mut() {
+                    refine_green(mosaic, planes, *direction);
+                    fill_red_blue(mosaic, planes);
+                }
+            }
+            candidates.extend(refined);
+        }
+
+        select(mosaic, &candidates)
+    }
+}
+
+/// Copy of the base planes with green at non-green photosites interpolated from the nearest greens along a line
+fn green_along(mosaic: &Mosaic, base: &Planes, (dx, dy): (isize, isize)) -> Planes {
+    let mut planes = base.clone();
+
+    planes.planes[1] = plane_from_fn(mosaic.width, mosaic.height, |x, y| {
+        if mosaic.color_at(x, y) == 1 {
+            return base.get(1, x as isize, y as isize);
+        }
+
+        let mut sum = 0.0;
+        let mut weights = 0.0;
+        for sign in [-1, 1] {
+            for distance in 1..=REACH {
+                let ax = mirror(x as isize + sign * distance * dx, mosaic.width);
+                let ay = mirror(y as isize + sign * distance * dy, mosaic.height);
+                if mosaic.color_at(ax, ay) == 1 {
+                    let weight = 1.0 / distance as f32;
+                    sum += base.get(1, ax as isize, ay as isize) * weight;
+                    weights += weight;
+                    break;
+                }
+            }
+        }
+
+        if weights > 0.0 {
+            sum / weights
+        } else {
+            // No green along this line, use the surroundings instead
+            bilinear::interpolate(mosaic, x, y, 1)
+        }
+    });
+
+    planes
+}
+
+/// Estimate green again at non-green photosites, from the color differences of the two closest neighbours along a line
+fn refine_green(mosaic: &Mosaic, planes: &mut Planes, (dx, dy): (isize, isize)) {
+    let previous = &*planes;
+
+    let green = plane_from_fn(mosaic.width, mosaic.height, |x, y| {
+        let own_color = mosaic.color_at(x, y);
+        let (xi, yi) = (x as isize, y as isize);
+        if (own_color == 1) | (own_color > 2) {
+            return previous.get(1, xi, yi);
+        }
+
+        let before = (xi - dx, yi - dy);
+        let after = (xi + dx, yi + dy);
+        let difference = (previous.get(1, before.0, before.1)
+            - previous.get(own_color, before.0, before.1)
+            + previous.get(1, after.0, after.1)
+            - previous.get(own_color, after.0, after.1))
+            / 2.0;
+
+        limit(
+            previous.get(own_color, xi, yi) + difference,
+            previous.get(1, before.0, before.1),
+            previous.get(1, after.0, after.1),
+        )
+    });
+    planes.planes[1] = green;
+}
+
+/// Interpolate red and blue wherever they are missing, from distance-weighted color differences in a 5x5 window
+fn fill_red_blue(mosaic: &Mosaic, planes: &mut Planes) {
+    for color in [0, 2] {
+        let plane = plane_from_fn(mosaic.width, mosaic.height, |x, y| {
+            let (xi, yi) = (x as isize, y as isize);
+            if mosaic.color_at(x, y) == color {
+                return planes.get(color, xi, yi);
+            }
+
+            let mut sum = 0.0;
+            let mut weights = 0.0;
+            for dy in -2isize..=2 {
+                for dx in -2isize..=2 {
+                    let ax = mirror(xi + dx, mosaic.width);
+                    let ay = mirror(yi + dy, mosaic.height);
+                    if ((dx == 0) & (dy == 0)) || mosaic.color_at(ax, ay) != color {
+                        continue;
+                    }
+                    let weight = 1.0 / (dx * dx + dy * dy) as f32;
+                    sum += (planes.get(color, ax as isize, ay as isize)
+                        - planes.get(1, ax as isize, ay as isize))
+                        * weight;
+                    weights += weight;
+                }
+            }
+
+            if weights > 0.0 {
+                planes.get(1, xi, yi) + sum / weights
+            } else {
+                planes.get(color, xi, yi)
+            }
+        });
+        planes.planes[color] = plane;
+    }
+}
+
+/// Average, for each pixel, the candidates whose neighbourhood is the most homogeneous
+fn select(mosaic: &Mosaic, candidates: &[(Planes, (isize, isize))]) -> RgbImage {
+    let width = mosaic.width;
+    let height = mosaic.height;
+    let index = |x: isize, y: isize| width * mirror(y, height) + mirror(x, width);
+
+    // Squared Lab second derivative along the direction each candidate was built with
+    let derivatives: Vec<Vec<f32>> = candidates
+        .iter()
+        .map(|(planes, (dx, dy))| {
+            let lab = to_lab(planes);
+            plane_from_fn(width, height, |x, y| {
+                let (x, y) = (x as isize, y as isize);
+                let center = lab[index(x, y)];
+                let before = lab[index(x - dx, y - dy)];
+                let after = lab[index(x + dx, y + dy)];
+                (0..3)
+                    .map(|c| (2.0 * center[c] - before[c] - after[c]).powi(2))
+                    .sum::<f32>()
+            })
+        })
+        .collect();
+
+    // About as smooth as the smoothest candidate
+    let thresholds = plane_from_fn(width, height, |x, y| {
+        derivatives
+            .iter()
+            .map(|derivative| derivative[width * y + x])
+            .fold(f32::INFINITY, f32::min)
+            * 8.0
+    });
+
+    // Number of pixels in the 3x3 window under the threshold
+    let homogeneity: Vec<Vec<u8>> = derivatives
+        .iter()
+        .map(|derivative| {
+            plane_from_fn(width, height, |x, y| {
+                let threshold = thresholds[width * y + x];
+                let (x, y) = (x as isize, y as isize);
+                let mut count = 0u8;
+                for dy in -1..=1 {
+                    for dx in -1..=1 {
+                        if derivative[index(x + dx, y + dy)] <= threshold {
+                            count += 1;
+                        }
+                    }
+                }
+                count
+            })
+        })
+        .collect();
+
+    RgbImage::from_fn(width, height, |x, y| {
+        let (x, y) = (x as isize, y as isize);
+        let scores: Vec<u32> = homogeneity
+            .iter()
+            .map(|map| {
+                let mut score = 0;
+                for dy in -2..=2 {
+                    for dx in -2..=2 {
+                        score += map[index(x + dx, y + dy)] as u32;
+                    }
+                }
+                score
+            })
+            .collect();
+
+        let best = scores.iter().copied().max().unwrap_or(0);
+        let threshold = best - best / 8;
+
+        let mut rgb = [0.0f32; 3];
+        let mut count = 0;
+        for ((planes, _), &score) in candidates.iter().zip(&scores) {
+            if score < threshold {
+                continue;
+            }
+            for (value, plane) in rgb.iter_mut().zip(&planes.planes) {
+                *value += plane[index(x, y)];
+            }
+            count += 1;
+        }
+
+        (
+            rgb[0] / count as f32,
+            rgb[1] / count as f32,
+            rgb[2] / count as f32,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demosaic::tests::{assert_flat_field, assert_no_zipper, BAYER, X_TRANS};
+
+    #[test]
+    fn flat_field() {
+        for passes in [1, 3] {
+            assert_flat_field(&Markesteijn { passes });
+        }
+    }
+
+    #[test]
+    fn no_zipper() {
+        for passes in [1, 3] {
+            assert_no_zipper(&Markesteijn { passes }, &[X_TRANS]);
+            assert_no_zipper(&Markesteijn { passes }, &BAYER);
+        }
+    }
+}
@@ -0,0 +1,284 @@
+pub mod ahd;
+pub mod bilinear;
+pub mod markesteijn;
+pub mod ppg;
+pub mod vng;
+
+use std::fmt::Debug;
+
+use clap::ValueEnum;
+use rawloader::CFA;
+use rayon::prelude::*;
+
+use crate::buffer::{Mosaic, RgbImage};
+
+pub use ahd::Ahd;
+pub use bilinear::Bilinear;
+pub use markesteijn::Markesteijn;
+pub use ppg::Ppg;
+pub use vng::Vng;
+
+/// CFA color indices that map to output channels
+pub const RGB_COLORS: [usize; 3] = [0, 1, 2];
+
+/// A demosaicing algorithm
+pub trait Demosaic: Debug {
+    /// Rebuild full RGB pixels from given mosaic
+    fn demosaic(&self, mosaic: &Mosaic) -> RgbImage;
+}
+
+/// Demosaicing algorithms available from the command line
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum DemosaicMethod {
+    /// Bilinear for 2x2 patterns, 1-pass Markesteijn for larger ones such as X-Trans
+    #[default]
+    Auto,
+    /// Average of nearest same-colored photosites, works with any CFA
+    Bilinear,
+    /// Adaptive Homogeneity-Directed, Bayer only
+    Ahd,
+    /// Variable Number of Gradients, Bayer only
+    Vng,
+    /// Patterned Pixel Grouping, Bayer only
+    Ppg,
+    /// Markesteijn 1-pass, meant for X-Trans
+    Markesteijn,
+    /// Markesteijn 3-pass, slower but cleaner than 1-pass
+    Markesteijn3,
+}
+
+impl DemosaicMethod {
+    /// Algorithm to use for given pattern. Bayer-only methods are swapped for Markesteijn on larger patterns.
+    pub fn algorithm(self, cfa: &CFA) -> Box<dyn Demosaic> {
+        let two_by_two = (cfa.width == 2) & (cfa.height == 2);
+        match self {
+            DemosaicMethod::Auto if two_by_two => Box::new(Bilinear),
+            DemosaicMethod::Bilinear => Box::new(Bilinear),
+            DemosaicMethod::Ahd if two_by_two => Box::new(Ahd),
+            DemosaicMethod::Vng if two_by_two => Box::new(Vng),
+            DemosaicMethod::Ppg if two_by_two => Box::new(Ppg),
+            DemosaicMethod::Markesteijn3 => Box::new(Markesteijn { passes: 3 }),
+            _ => Box::new(Markesteijn { passes: 1 }),
+        }
+    }
+}
+
+/// Rebuilds full RGB pixels from a mosaic
+#[derive(Debug, Clone, Default)]
+pub struct Demosaicer {
+    pub method: DemosaicMethod,
+}
+
+impl Demosaicer {
+    pub fn demosaic(&self, mosaic: &Mosaic) -> RgbImage {
+        self.method.algorithm(&mosaic.cfa).demosaic(mosaic)
+    }
+}
+
+/// Reflect a position that may be out of bounds back into [0, size), without repeating the edge sample
+pub fn mirror(position: isize, size: usize) -> usize {
+    let last = size as isize - 1;
+    let mut position = position;
+    if position < 0 {
+        position = -position;
+    }
+    if position > last {
+        position = 2 * last - position;
+    }
+    position.clamp(0, last.max(0)) as usize
+}
+
+/// Whether the pattern is a 2x2 Bayer layout (RGGB, BGGR, GRBG or GBRG)
+pub fn is_bayer(cfa: &CFA) -> bool {
+    if (cfa.width != 2) | (cfa.height != 2) {
+        return false;
+    }
+    let mut colors = [
+        cfa.color_at(0, 0),
+        cfa.color_at(0, 1),
+        cfa.color_at(1, 0),
+        cfa.color_at(1, 1),
+    ];
+    // Greens must sit on a diagonal
+    let greens_diagonal =
+        ((colors[0] == 1) & (colors[3] == 1)) | ((colors[1] == 1) & (colors[2] == 1));
+    colors.sort_unstable();
+    greens_diagonal & (colors == [0, 1, 1, 2])
+}
+
+/// Working buffer for Bayer algorithms: every sample of the mosaic copied into the plane of its color
+#[derive(Clone)]
+pub(crate) struct Planes {
+    pub width: usize,
+    pub height: usize,
+    pub planes: [Vec<f32>; 3],
+}
+
+impl Planes {
+    pub fn from_mosaic(mosaic: &Mosaic) -> Planes {
+        let size = mosaic.width * mosaic.height;
+        let mut planes = [vec![0.0; size], vec![0.0; size], vec![0.0; size]];
+        for y in 0..mosaic.height {
+            for x in 0..mosaic.width {
+                let color = mosaic.color_at(x, y);
+                if color < 3 {
+                    planes[color][mosaic.width * y + x] = mosaic.at(x, y);
+                }
+            }
+        }
+        Planes {
+            width: mosaic.width,
+            height: mosaic.height,
+            planes,
+        }
+    }
+
+    /// Value of a plane at given position, mirrored back in if out of bounds.
+    ///
+    /// Mirroring keeps the parity of coordinates, so the Bayer color of the position is preserved.
+    pub fn get(&self, color: usize, x: isize, y: isize) -> f32 {
+        let x = mirror(x, self.width);
+        let y = mirror(y, self.height);
+        self.planes[color][self.width * y + x]
+    }
+
+    pub fn into_rgb(self) -> RgbImage {
+        let [red, green, blue] = self.planes;
+        RgbImage {
+            width: self.width,
+            height: self.height,
+            red,
+            green,
+            blue,
+        }
+    }
+}
+
+/// Approximate CIELab of every pixel, treating camera RGB as linear sRGB
+pub(crate) fn to_lab(planes: &Planes) -> Vec<[f32; 3]> {
+    let [red, green, blue] = &planes.planes;
+
+    let f = |t: f32| {
+        if t > 0.008856 {
+            t.cbrt()
+        } else {
+            7.787 * t + 16.0 / 116.0
+        }
+    };
+
+    red.par_iter()
+        .zip(green)
+        .zip(blue)
+        .map(|((&r, &g), &b)| {
+            // sRGB to XYZ, relative to the D65 white
+            let x = (0.412_456 * r + 0.357_576 * g + 0.180_438 * b) / 0.950_47;
+            let y = 0.212_673 * r + 0.715_152 * g + 0.072_175 * b;
+            let z = (0.019_334 * r + 0.119_192 * g + 0.950_304 * b) / 1.088_83;
+
+            let (fx, fy, fz) = (f(x), f(y), f(z));
+            [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+        })
+        .collect()
+}
+
+/// Clamp a value between two bounds given in any order
+pub(crate) fn limit(value: f32, a: f32, b: f32) -> f32 {
+    value.clamp(a.min(b), a.max(b))
+}
+
+/// Synthetic mosaics and checks shared by the tests of every algorithm
+#[cfg(test)]
+pub(crate) mod tests {
+    use rawloader::CFA;
+
+    use super::*;
+    use crate::buffer::plane_from_fn;
+
+    pub const BAYER: [&str; 4] = ["RGGB", "BGGR", "GRBG", "GBRG"];
+
+    /// Fujifilm X-Trans pattern
+    pub const X_TRANS: &str = "GGRGGBGGBGGRBRGRBGGGBGGRGGRGGBRBGBRG";
+
+    /// Mosaic of a scene, every photosite taking the component of its color at its position
+    pub fn mosaic(
+        pattern: &str,
+        width: usize,
+        height: usize,
+        scene: impl Fn(usize, usize) -> [f32; 3] + Sync,
+    ) -> Mosaic {
+        let cfa = CFA::new(pattern);
+        let data = plane_from_fn(width, height, |x, y| scene(x, y)[cfa.color_at(y, x)]);
+        Mosaic {
+            width,
+            height,
+            cfa,
+            data,
+        }
+    }
+
+    /// Check every pixel against the scene
+    pub fn assert_scene(image: &RgbImage, scene: impl Fn(usize, usize) -> [f32; 3], label: &str) {
+        for y in 0..image.height {
+            for x in 0..image.width {
+                let (r, g, b) = image.pixel(x, y);
+                let expected = scene(x, y);
+                assert!(
+                    [r, g, b]
+                        .iter()
+                        .zip(expected)
+                        .all(|(value, expected)| (value - expected).abs() < 1e-5),
+                    "{label}: pixel ({x}, {y}) is {:?}, expected {expected:?}",
+                    (r, g, b)
+                );
+            }
+        }
+    }
+
+    /// A field of one color comes out unchanged on every Bayer layout and on X-Trans, borders included
+    pub fn assert_flat_field(algorithm: &dyn Demosaic) {
+        let color = |_, _| [0.2, 0.5, 0.8];
+        for pattern in BAYER.into_iter().chain([X_TRANS]) {
+            // Odd sizes, so patterns are cut off at the right and bottom borders
+            let image = algorithm.demosaic(&mosaic(pattern, 13, 9, color));
+            assert_scene(&image, color, pattern);
+        }
+    }
+
+    /// A sharp grey edge, vertical then horizontal, comes out the same all along it, without the alternating colors of zippering.
+    ///
+    /// Pixels more than one away from the edge keep their value.
+    pub fn assert_no_zipper(algorithm: &dyn Demosaic, patterns: &[&str]) {
+        const SIZE: usize = 12;
+        let grey = |position: usize| [if position < SIZE / 2 { 0.2 } else { 0.8 }; 3];
+        let near_edge = |position: usize| (SIZE / 2 - 2..SIZE / 2 + 2).contains(&position);
+        for &pattern in patterns {
+            for vertical in [true, false] {
+                // Position across the edge, and along it
+                let axes = |x: usize, y: usize| if vertical { (x, y) } else { (y, x) };
+                let image =
+                    algorithm.demosaic(&mosaic(pattern, SIZE, SIZE, |x, y| grey(axes(x, y).0)));
+                for y in 0..SIZE {
+                    for x in 0..SIZE {
+                        let (across, _) = axes(x, y);
+                        let expected = if near_edge(across) {
+                            // First pixel along the edge
+                            let (first_x, first_y) = axes(across, 0);
+                            let first = image.pixel(first_x, first_y);
+                            [first.0, first.1, first.2]
+                        } else {
+                            grey(across)
+                        };
+                        let pixel = image.pixel(x, y);
+                        assert!(
+                            [pixel.0, pixel.1, pixel.2]
+                                .iter()
+                                .zip(expected)
+                                .all(|(value, expected)| (value - expected).abs() < 1e-5),
+                            "{pattern}: pixel ({x}, {y}) is {pixel:?}, expected {expected:?}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use crate::buffer::{plane_from_fn, Mosaic, RgbImage};
+
+use super::{is_bayer, limit, Bilinear, Demosaic, Planes};
+
+/// Patterned Pixel Grouping (Chuan-kai Lin), interpolating along the direction with the smallest gradient.
+///
+/// Falls back to [`Bilinear`] for non-Bayer patterns.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Ppg;
+
+/// Horizontal then vertical unit steps
+const DIRECTIONS: [(isize, isize); 2] = [(1, 0), (0, 1)];
+
+impl Demosaic for Ppg {
+    fn demosaic(&self, mosaic: &Mosaic) -> RgbImage {
+        if !is_bayer(&mosaic.cfa) {
+            return Bilinear.demosaic(mosaic);
+        }
+
+        let (width, height) = (mosaic.width, mosaic.height);
+        let mut planes = Planes::from_mosaic(mosaic);
+
+        // Fill in green at red and blue photosites
+        let green = plane_from_fn(width, height, |x, y| {
+            let c = mosaic.color_at(x, y);
+            let (xi, yi) = (x as isize, y as isize);
+            if c == 1 {
+                return planes.get(1, xi, yi);
+            }
+            let p = |color, n: isize, (dx, dy): (isize, isize)| {
+                planes.get(color, xi + n * dx, yi + n * dy)
+            };
+
+            let mut guesses = [0.0; 2];
+            let mut diffs = [0.0; 2];
+            for (i, &d) in DIRECTIONS.iter().enumerate() {
+                guesses[i] =
+                    ((p(1, -1, d) + p(c, 0, d) + p(1, 1, d)) * 2.0 - p(c, -2, d) - p(c, 2, d))
+                        / 4.0;
+                diffs[i] = ((p(c, -2, d) - p(c, 0, d)).abs()
+                    + (p(c, 2, d) - p(c, 0, d)).abs()
+                    + (p(1, -1, d) - p(1, 1, d)).abs())
+                    * 3.0
+                    + ((p(1, 3, d) - p(1, 1, d)).abs() + (p(1, -3, d) - p(1, -1, d)).abs()) * 2.0;
+            }
+
+            let i = usize::from(diffs[0] > diffs[1]);
+            let d = DIRECTIONS[i];
+            limit(guesses[i], p(1, 1, d), p(1, -1, d))
+        });
+        planes.planes[1] = green;
+
+        // Fill in red and blue at green photosites
+        for c in [0, 2] {
+            let plane = plane_from_fn(width, height, |x, y| {
+                let (xi, yi) = (x as isize, y as isize);
+                if mosaic.color_at(x, y) != 1 {
+                    return planes.get(c, xi, yi);
+                }
+                // Neighbours of that color are either on the same row or the same column
+                let (dx, dy) = if mosaic.color_at(x + 1, y) == c {
+                    DIRECTIONS[0]
+                } else {
+                    DIRECTIONS[1]
+                };
+                (planes.get(c, xi - dx, yi - dy)
+                    + planes.get(c, xi + dx, yi + dy)
+                    + 2.0 * planes.get(1, xi, yi)
+                    - planes.get(1, xi - dx, yi - dy)
+                    - planes.get(1, xi + dx, yi + dy))
+                    / 2.0
+            });
+            planes.planes[c] = plane;
+        }
+
+        // Fill in blue at red photosites and red at blue photosites
+        for c in [0, 2] {
+            let plane = plane_from_fn(width, height, |x, y| {
+                let own = mosaic.color_at(x, y);
+                let (xi, yi) = (x as isize, y as isize);
+                if (own == 1) | (own == c) {
+                    return planes.get(c, xi, yi);
+                }
+
+                let green = planes.get(1, xi, yi);
+                let mut guesses = [0.0; 2];
+                let mut diffs = [0.0; 2];
+                // Both diagonals
+                for (i, (dx, dy)) in [(1isize, 1isize), (-1, 1)].into_iter().enumerate() {
+                    let before = (xi - dx, yi - dy);
+                    let after = (xi + dx, yi + dy);
+                    diffs[i] =
+                        (planes.get(c, before.0, before.1) - planes.get(c, after.0, after.1)).abs()
+                            + (planes.get(1, before.0, before.1) - green).abs()
+                            + (planes.get(1, after.0, after.1) - green).abs();
+                    guesses[i] = planes.get(c, before.0, before.1)
+                        + planes.get(c, after.0, after.1)
+                        + 2.0 * green
+                        - planes.get(1, before.0, before.1)
+                        - planes.get(1, after.0, after.1);
+                }
+
+                if diffs[0] != diffs[1] {
+                    guesses[usize::from(diffs[0] > diffs[1])] / 2.0
+                } else {
+                    (guesses[0] + guesses[1]) / 4.0
+                }
+            });
+            planes.planes[c] = plane;
+        }
+
+        planes.into_rgb()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demosaic::tests::{assert_flat_field, assert_no_zipper, BAYER};
+
+    #[test]
+    fn flat_field() {
+        assert_flat_field(&Ppg);
+    }
+
+    #[test]
+    fn no_zipper() {
+        assert_no_zipper(&Ppg, &BAYER);
+    }
+}
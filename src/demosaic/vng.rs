@@ -0,0 +1,155 @@
+use crate::buffer::{Mosaic, RgbImage};
+
+use super::{is_bayer, mirror, Bilinear, Demosaic};
+
+/// Variable Number of Gradients (Chang, Cheung and Pang), averaging color differences only along the smoothest directions.
+///
+/// As in dcraw, the colors of each direction come from the next pixel along it, interpolated bilinearly.
+///
+/// Falls back to [`Bilinear`] for non-Bayer patterns.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Vng;
+
+/// Two (dx, dy) offsets whose photosites are compared, and the weight of their difference
+type GradientPair = ((isize, isize), (isize, isize), f32);
+
+/// Pairs of (dx, dy) offsets compared for the North gradient, with their weight
+const NORTH_GRADIENT: [GradientPair; 6] = [
+    ((0, -1), (0, 1), 1.0),
+    ((0, -2), (0, 0), 1.0),
+    ((-1, -1), (-1, 1), 0.5),
+    ((1, -1), (1, 1), 0.5),
+    ((-1, -2), (-1, 0), 0.5),
+    ((1, -2), (1, 0), 0.5),
+];
+
+/// Pairs of (dx, dy) offsets compared for the North-East gradient, with their weight. Pairs of different colors are skipped.
+const NORTH_EAST_GRADIENT: [GradientPair; 6] = [
+    ((1, -1), (-1, 1), 1.0),
+    ((2, -2), (0, 0), 1.0),
+    ((0, -1), (-1, 0), 0.5),
+    ((1, 0), (0, 1), 0.5),
+    ((1, -2), (0, -1), 0.5),
+    ((2, -1), (1, 0), 0.5),
+];
+
+/// Relative margin within which a gradient counts as equal to the threshold, so that ties don't depend on rounding
+const TIE_MARGIN: f32 = 1e-4;
+
+/// Unit steps towards North and North-East, rotated for the other directions
+const NORTH: (isize, isize) = (0, -1);
+const NORTH_EAST: (isize, isize) = (1, -1);
+
+/// Rotate an offset by a quarter turn clockwise, `turns` times
+fn rotate((dx, dy): (isize, isize), turns: usize) -> (isize, isize) {
+    let (mut dx, mut dy) = (dx, dy);
+    for _ in 0..turns {
+        (dx, dy) = (-dy, dx);
+    }
+    (dx, dy)
+}
+
+impl Demosaic for Vng {
+    fn demosaic(&self, mosaic: &Mosaic) -> RgbImage {
+        if !is_bayer(&mosaic.cfa) {
+            return Bilinear.demosaic(mosaic);
+        }
+        let base = Bilinear.demosaic(mosaic);
+
+        // Position and color of a photosite relative to the current one
+        let position = |x: usize, y: usize, (dx, dy): (isize, isize)| {
+            (
+                mirror(x as isize + dx, mosaic.width),
+                mirror(y as isize + dy, mosaic.height),
+            )
+        };
+        let sample = |x: usize, y: usize, offset: (isize, isize)| {
+            let (ax, ay) = position(x, y, offset);
+            (mosaic.at(ax, ay), mosaic.color_at(ax, ay))
+        };
+
+        RgbImage::from_fn(mosaic.width, mosaic.height, |x, y| {
+            let own_color = mosaic.color_at(x, y);
+            let own_value = mosaic.at(x, y);
+
+            // N, E, S, W then NE, SE, SW, NW
+            let mut gradients = [0.0f32; 8];
+            for (direction, gradient) in gradients.iter_mut().enumerate() {
+                let template = if direction < 4 {
+                    &NORTH_GRADIENT
+                } else {
+                    &NORTH_EAST_GRADIENT
+                };
+                // Differences across colors measure chroma, not structure, so they are skipped as in dcraw.
+                // The others are averaged, so directions that skip some stay comparable with the rest.
+                let mut weights = 0.0;
+                for &(a, b, weight) in template {
+                    let (a, a_color) = sample(x, y, rotate(a, direction % 4));
+                    let (b, b_color) = sample(x, y, rotate(b, direction % 4));
+                    if a_color == b_color {
+                        *gradient += (a - b).abs() * weight;
+                        weights += weight;
+                    }
+                }
+                if weights > 0.0 {
+                    *gradient /= weights;
+                }
+            }
+
+            let min = gradients.iter().copied().fold(f32::INFINITY, f32::min);
+            let max = gradients.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let threshold = 1.5 * min + 0.5 * (max - min);
+
+            // Sum of the colors of the next pixel along selected directions, from the bilinear base.
+            // The own color is the average of the photosite and the next one of that color instead.
+            let mut sums = [0.0f32; 3];
+            let mut selected = 0;
+            for (direction, &gradient) in gradients.iter().enumerate() {
+                if gradient > threshold * (1.0 + TIE_MARGIN) {
+                    continue;
+                }
+                let step = rotate(
+                    if direction < 4 { NORTH } else { NORTH_EAST },
+                    direction % 4,
+                );
+                let (nx, ny) = position(x, y, step);
+                let (r, g, b) = base.pixel(nx, ny);
+                let next = [r, g, b];
+                for (color, sum) in sums.iter_mut().enumerate() {
+                    *sum += if color == own_color {
+                        let (further, _) = sample(x, y, (2 * step.0, 2 * step.1));
+                        (own_value + further) / 2.0
+                    } else {
+                        next[color]
+                    };
+                }
+                selected += 1;
+            }
+
+            let mut rgb = [own_value; 3];
+            if selected > 0 {
+                for (color, value) in rgb.iter_mut().enumerate() {
+                    *value = own_value + (sums[color] - sums[own_color]) / selected as f32;
+                }
+            }
+
+            (rgb[0], rgb[1], rgb[2])
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demosaic::tests::{assert_flat_field, assert_no_zipper, BAYER};
+
+    #[test]
+    fn flat_field() {
+        assert_flat_field(&Vng);
+    }
+
+    #[test]
+    fn no_zipper() {
+        assert_no_zipper(&Vng, &BAYER);
+    }
+}
@@ -0,0 +1,461 @@
+use std::path::Path;
+
+use clap::ValueEnum;
+use exr::{
+    image::{
+        AnyChannel, AnyChannels, Blocks, Encoding, FlatSamples, Image, Layer, Levels, RipMaps,
+    },
+    math::{RoundingMode, Vec2},
+    meta::attribute::{AttributeValue, Chromaticities, LineOrder, Text},
+    prelude::{f16, ImageAttributes, IntegerBounds, LayerAttributes, WritableImage},
+};
+
+use crate::{
+    buffer::{Mosaic, RgbImage},
+    error::{Raw2ExrError, Result},
+    levels::{level_count, level_size, resize, Filter, LevelMode},
+    metadata::{Metadata, CROPS_ATTRIBUTE},
+};
+
+/// Largest finite half float
+pub const HALF_MAX: f32 = 65504.0;
+
+/// Name of the layer holding the CFA mosaic
+pub const MOSAIC_LAYER: &str = "CFA Mosaic";
+
+/// Name of the single channel of the mosaic layer
+pub const MOSAIC_CHANNEL: &str = "CFA";
+
+/// Tile size used when levels are asked for without a tile size
+pub const DEFAULT_TILE_SIZE: usize = 64;
+
+/// EXR compression methods the exr crate can write. DWAA and DWAB are not available there.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ExrCompression {
+    /// No compression
+    None,
+    /// Run-length encoding, lossless
+    Rle,
+    /// Deflate, one scanline at a time, lossless
+    Zips,
+    /// Deflate, 16 scanlines at a time, lossless
+    Zip,
+    /// Wavelet, lossless, good on noisy images
+    #[default]
+    Piz,
+    /// Lossy for float samples (24 bits kept), lossless for half
+    Pxr24,
+    /// Lossy, fixed rate, half samples only
+    B44,
+    /// Like B44, flat areas compress better
+    B44a,
+}
+
+impl ExrCompression {
+    pub fn to_exr(self) -> exr::compression::Compression {
+        use exr::compression::Compression;
+        match self {
+            ExrCompression::None => Compression::Uncompressed,
+            ExrCompression::Rle => Compression::RLE,
+            ExrCompression::Zips => Compression::ZIP1,
+            ExrCompression::Zip => Compression::ZIP16,
+            ExrCompression::Piz => Compression::PIZ,
+            ExrCompression::Pxr24 => Compression::PXR24,
+            ExrCompression::B44 => Compression::B44,
+            ExrCompression::B44a => Compression::B44A,
+        }
+    }
+
+    /// This compression if it keeps float samples exact, zip otherwise
+    pub fn lossless(self) -> ExrCompression {
+        match self {
+            ExrCompression::Pxr24 | ExrCompression::B44 | ExrCompression::B44a => {
+                ExrCompression::Zip
+            }
+            lossless => lossless,
+        }
+    }
+}
+
+/// Sample type written in the file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Precision {
+    /// 16-bit half float, values beyond ±65504 are clamped
+    #[default]
+    Half,
+    /// 32-bit float
+    Float,
+}
+
+/// How the active area of the sensor, given by the raw file's crops, ends up in the file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum CropMode {
+    /// Full sensor, display window covers everything
+    None,
+    /// Full sensor, display window limited to the active area
+    #[default]
+    Display,
+    /// Only the active area is written
+    Data,
+}
+
+/// Last stage, writes an RGB image as an OpenEXR file
+#[derive(Debug, Clone, Default)]
+pub struct ExrEncoder {
+    pub compression: ExrCompression,
+    pub precision: Precision,
+    /// Write square tiles of this size instead of scanlines
+    pub tile_size: Option<usize>,
+    /// Lower resolution levels to add, always tiled
+    pub levels: LevelMode,
+    /// Filter for lower resolution levels
+    pub filter: Filter,
+    pub crop: CropMode,
+    /// Also write the normalized, not yet white balanced mosaic as a single channel layer, always as lossless 32-bit float
+    pub mosaic_layer: bool,
+    /// Also write a mask of clipped highlights
+    pub mask_layer: bool,
+}
+
+/// Layers written next to the "RAW Image" layer, when given
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExtraLayers<'a> {
+    /// Single channel "CFA Mosaic" layer
+    pub mosaic: Option<&'a Mosaic>,
+    /// "Clipping Mask" layer, 1.0 in channels that reached white
+    pub mask: Option<&'a RgbImage>,
+}
+
+impl ExrEncoder {
+    /// Write the image and extra layers. Returns how many samples of the image had to be clamped to fit the precision
+    pub fn encode(
+        &self,
+        image: &RgbImage,
+        extra: ExtraLayers,
+        crops: [usize; 4],
+        chromaticities: Chromaticities,
+        metadata: &Metadata,
+        path: &Path,
+    ) -> Result<usize> {
+        let (cropped_image, cropped_mosaic, cropped_mask);
+        let (image, extra, display_window) = match self.crop {
+            CropMode::None => (
+                image,
+                extra,
+                IntegerBounds::from_dimensions((image.width, image.height)),
+            ),
+            CropMode::Display => (image, extra, active_area(crops, image.width, image.height)?),
+            CropMode::Data => {
+                let active = active_area(crops, image.width, image.height)?;
+                let (left, top) = (active.position.0 as usize, active.position.1 as usize);
+                let (width, height) = (active.size.0, active.size.1);
+                cropped_image = image.crop(left, top, width, height);
+                cropped_mosaic = extra
+                    .mosaic
+                    .map(|mosaic| mosaic.crop(left, top, width, height));
+                cropped_mask = extra.mask.map(|mask| mask.crop(left, top, width, height));
+                (
+                    &cropped_image,
+                    ExtraLayers {
+                        mosaic: cropped_mosaic.as_ref(),
+                        mask: cropped_mask.as_ref(),
+                    },
+                    IntegerBounds::from_dimensions((width, height)),
+                )
+            }
+        };
+
+        let blocks = match (self.tile_size, self.levels) {
+            (Some(0), _) => {
+                return Err(Raw2ExrError::InvalidInput(
+                    "tile size must be at least 1".to_string(),
+                ))
+            }
+            (Some(size), _) => Blocks::Tiles(Vec2(size, size)),
+            (None, LevelMode::Single) => Blocks::ScanLines,
+            (None, _) => Blocks::Tiles(Vec2(DEFAULT_TILE_SIZE, DEFAULT_TILE_SIZE)),
+        };
+
+        let encoding = Encoding {
+            compression: self.compression.to_exr(),
+            blocks,
+            line_order: LineOrder::Increasing,
+        };
+
+        let mut attributes = LayerAttributes::named("RAW Image");
+        metadata.apply(&mut attributes);
+        if self.crop == CropMode::Data {
+            // Nothing is left to crop
+            attributes.other.remove(&Text::from(CROPS_ATTRIBUTE));
+        }
+        let (channels, clamped) = self.rgb_channels(image);
+        let layer = Layer::new((image.width, image.height), attributes, encoding, channels);
+        let mut layers = vec![layer];
+
+        if let Some(mosaic) = extra.mosaic {
+            // Kept exact whatever the precision, for re-demosaicing and calibration masters
+            let samples = FlatSamples::F32(mosaic.data.clone());
+            let encoding = Encoding {
+                compression: self.compression.lossless().to_exr(),
+                ..encoding
+            };
+
+            let mut attributes = LayerAttributes::named(MOSAIC_LAYER);
+            attributes.other.insert(
+                Text::from("raw2exr/cfaPattern"),
+                AttributeValue::Text(Text::from(mosaic.pattern().as_str())),
+            );
+            attributes.other.insert(
+                Text::from("raw2exr/cfaSize"),
+                AttributeValue::IntVec2(Vec2(mosaic.cfa.width as i32, mosaic.cfa.height as i32)),
+            );
+
+            // Downsampling would mix photosites of different colors, so full resolution only
+            layers.push(Layer::new(
+                (mosaic.width, mosaic.height),
+                attributes,
+                encoding,
+                AnyChannels::sort(
+                    vec![AnyChannel::new(MOSAIC_CHANNEL, Levels::Singular(samples))].into(),
+                ),
+            ));
+        }
+
+        if let Some(mask) = extra.mask {
+            layers.push(Layer::new(
+                (mask.width, mask.height),
+                LayerAttributes::named("Clipping Mask"),
+                encoding,
+                self.rgb_channels(mask).0,
+            ));
+        }
+
+        let mut image_attributes = ImageAttributes::new(display_window);
+        image_attributes.pixel_aspect = 1.0;
+        image_attributes.chromaticities = Some(chromaticities);
+        let exr_image = Image::from_layers(image_attributes, layers);
+
+        exr_image
+            .write()
+            .to_file(path)
+            .map_err(|source| Raw2ExrError::ExrWrite {
+                path: path.to_path_buf(),
+                source,
+            })?;
+
+        Ok(clamped)
+    }
+
+    /// R, G and B channels of an image, with the number of clamped samples
+    fn rgb_channels(&self, image: &RgbImage) -> (AnyChannels<Levels<FlatSamples>>, usize) {
+        let mut clamped = 0;
+        let mut channel = |name: &str, plane: &[f32]| {
+            let (levels, plane_clamped) = self.levels(plane, image.width, image.height);
+            clamped += plane_clamped;
+            AnyChannel::new(name, levels)
+        };
+        let channels = AnyChannels::sort(
+            vec![
+                channel("R", &image.red),
+                channel("G", &image.green),
+                channel("B", &image.blue),
+            ]
+            .into(),
+        );
+        (channels, clamped)
+    }
+
+    /// Plane and its lower resolution levels in the output precision, with the number of clamped samples at full resolution
+    pub fn levels(
+        &self,
+        plane: &[f32],
+        width: usize,
+        height: usize,
+    ) -> (Levels<FlatSamples>, usize) {
+        let (full, clamped) = self.samples(plane);
+        let reduced = |level_x: usize, level_y: usize| {
+            let new_width = level_size(width, level_x);
+            let new_height = level_size(height, level_y);
+            self.samples(&resize(
+                plane,
+                width,
+                height,
+                new_width,
+                new_height,
+                self.filter,
+            ))
+            .0
+        };
+
+        let levels = match self.levels {
+            LevelMode::Single => Levels::Singular(full),
+            LevelMode::Mip => {
+                let mut maps = vec![full];
+                for level in 1..level_count(width.max(height)) {
+                    maps.push(reduced(level, level));
+                }
+                Levels::Mip {
+                    rounding_mode: RoundingMode::Down,
+                    level_data: maps,
+                }
+            }
+            LevelMode::Rip => {
+                let count = Vec2(level_count(width), level_count(height));
+                // Horizontal levels vary fastest
+                let mut maps = vec![full];
+                for level_y in 0..count.1 {
+                    for level_x in 0..count.0 {
+                        if level_x + level_y > 0 {
+                            maps.push(reduced(level_x, level_y));
+                        }
+                    }
+                }
+                Levels::Rip {
+                    rounding_mode: RoundingMode::Down,
+                    level_data: RipMaps {
+                        map_data: maps,
+                        level_count: count,
+                    },
+                }
+            }
+        };
+        (levels, clamped)
+    }
+
+    /// Plane converted to the output precision, with the number of clamped samples
+    pub fn samples(&self, plane: &[f32]) -> (FlatSamples, usize) {
+        match self.precision {
+            Precision::Float => (FlatSamples::F32(plane.to_vec()), 0),
+            Precision::Half => {
+                let clamped = plane.iter().filter(|value| value.abs() > HALF_MAX).count();
+                let samples = plane
+                    .iter()
+                    .map(|value| f16::from_f32(value.clamp(-HALF_MAX, HALF_MAX)))
+                    .collect();
+                (FlatSamples::F16(samples), clamped)
+            }
+        }
+    }
+}
+
+/// Active area of an image from its crops (top, right, bottom, left), checking that something is left
+pub fn active_area(crops: [usize; 4], width: usize, height: usize) -> Result<IntegerBounds> {
+    let [top, right, bottom, left] = crops;
+    let size = width
+        .checked_sub(left)
+        .and_then(|size| size.checked_sub(right))
+        .zip(
+            height
+                .checked_sub(top)
+                .and_then(|size| size.checked_sub(bottom)),
+        );
+    match size {
+        Some((active_width, active_height)) if (active_width > 0) & (active_height > 0) => {
+            Ok(IntegerBounds {
+                position: Vec2(left as i32, top as i32),
+                size: Vec2(active_width, active_height),
+            })
+        }
+        _ => Err(Raw2ExrError::UnsupportedLayout(format!(
+            "crops {crops:?} (top, right, bottom, left) leave nothing of a {width}x{height} image"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rawloader::CFA;
+
+    use super::*;
+    use crate::{buffer::plane_from_fn, calibrate::read_master, normalize::Normalized};
+
+    fn mosaic(pattern: &str, width: usize, height: usize) -> Mosaic {
+        Mosaic {
+            width,
+            height,
+            cfa: CFA::new(pattern),
+            data: plane_from_fn(width, height, |x, y| (width * y + x) as f32 + 0.123_456),
+        }
+    }
+
+    #[test]
+    fn odd_crops_shift_the_cfa() {
+        let mosaic = mosaic("RGGB", 5, 4);
+        assert_eq!(mosaic.crop(1, 0, 4, 4).pattern(), "GRBG");
+        assert_eq!(mosaic.crop(0, 1, 5, 3).pattern(), "GBRG");
+        let cropped = mosaic.crop(1, 1, 4, 3);
+        assert_eq!(cropped.pattern(), "BGGR");
+        assert_eq!(cropped.at(0, 0), mosaic.at(1, 1));
+    }
+
+    #[test]
+    fn active_area_of_odd_crops() {
+        let area = active_area([1, 0, 0, 1], 5, 4).unwrap();
+        assert_eq!(area.position, Vec2(1, 1));
+        assert_eq!(area.size, Vec2(4, 3));
+    }
+
+    #[test]
+    fn inconsistent_crops_are_an_error() {
+        for crops in [
+            [0, 3, 0, 3],
+            [2, 0, 2, 0],
+            [0, 6, 0, 0],
+            [usize::MAX, 0, 1, 0],
+        ] {
+            assert!(
+                matches!(
+                    active_area(crops, 5, 4),
+                    Err(Raw2ExrError::UnsupportedLayout(_))
+                ),
+                "{crops:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn data_crop_writes_a_shifted_exact_mosaic_without_crops() {
+        let mosaic = mosaic("RGGB", 5, 4);
+        let encoder = ExrEncoder {
+            crop: CropMode::Data,
+            mosaic_layer: true,
+            ..ExrEncoder::default()
+        };
+        let path =
+            std::env::temp_dir().join(format!("raw2exr-data-crop-{}.exr", std::process::id()));
+        let chromaticities = Chromaticities {
+            red: Vec2(0.64, 0.33),
+            green: Vec2(0.3, 0.6),
+            blue: Vec2(0.15, 0.06),
+            white: Vec2(0.3127, 0.329),
+        };
+        let mut metadata = Metadata::default();
+        metadata.set_crops([1, 0, 0, 1]);
+        encoder
+            .encode(
+                &RgbImage::new(5, 4),
+                ExtraLayers {
+                    mosaic: Some(&mosaic),
+                    mask: None,
+                },
+                [1, 0, 0, 1],
+                chromaticities,
+                &metadata,
+                &path,
+            )
+            .unwrap();
+        let written = read_master(&path);
+        let layers = exr::prelude::read_all_flat_layers_from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let attributes = &layers.unwrap().layer_data[0].attributes;
+        assert!(!attributes.other.contains_key(&Text::from(CROPS_ATTRIBUTE)));
+
+        let Normalized::Mosaic(written) = written.unwrap() else {
+            panic!("expected a mosaic");
+        };
+        assert_eq!((written.width, written.height), (4, 3));
+        assert_eq!(written.pattern(), "BGGR");
+        assert_eq!(written.data, mosaic.crop(1, 1, 4, 3).data);
+    }
+}
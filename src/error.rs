@@ -0,0 +1,77 @@
+use std::{error::Error, fmt, io, path::PathBuf};
+
+use crate::color::PrimariesError;
+
+/// Everything that can make a conversion fail
+#[derive(Debug)]
+pub enum Raw2ExrError {
+    /// A file or directory couldn't be accessed
+    Io { path: PathBuf, source: io::Error },
+    /// Input paths or patterns don't make sense
+    InvalidInput(String),
+    /// rawloader couldn't make sense of the file
+    Decode { path: PathBuf, message: String },
+    /// Raw data is laid out in a way that isn't handled, such as a CFA with colors other than red, green and blue
+    UnsupportedLayout(String),
+    /// Camera color matrix can't describe the image
+    BadColorMatrix(PrimariesError),
+    /// OpenEXR file couldn't be written
+    ExrWrite {
+        path: PathBuf,
+        source: exr::error::Error,
+    },
+}
+
+pub type Result<T, E = Raw2ExrError> = std::result::Result<T, E>;
+
+impl Raw2ExrError {
+    /// Process exit code for this kind of error. 1 and 2 are left to generic failures and command line errors.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Raw2ExrError::Io { .. } => 3,
+            Raw2ExrError::Decode { .. } => 4,
+            Raw2ExrError::UnsupportedLayout(_) => 5,
+            Raw2ExrError::BadColorMatrix(_) => 6,
+            Raw2ExrError::ExrWrite { .. } => 7,
+            Raw2ExrError::InvalidInput(_) => 8,
+        }
+    }
+}
+
+impl fmt::Display for Raw2ExrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Raw2ExrError::Io { path, source } => {
+                write!(f, "can't access {}: {source}", path.display())
+            }
+            Raw2ExrError::InvalidInput(message) => write!(f, "invalid input: {message}"),
+            Raw2ExrError::Decode { path, message } => {
+                write!(f, "can't decode {}: {message}", path.display())
+            }
+            Raw2ExrError::UnsupportedLayout(message) => {
+                write!(f, "unsupported raw data layout: {message}")
+            }
+            Raw2ExrError::BadColorMatrix(e) => write!(f, "bad camera color matrix: {e}"),
+            Raw2ExrError::ExrWrite { path, source } => {
+                write!(f, "can't write {}: {source}", path.display())
+            }
+        }
+    }
+}
+
+impl Error for Raw2ExrError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Raw2ExrError::Io { source, .. } => Some(source),
+            Raw2ExrError::BadColorMatrix(e) => Some(e),
+            Raw2ExrError::ExrWrite { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<PrimariesError> for Raw2ExrError {
+    fn from(e: PrimariesError) -> Self {
+        Raw2ExrError::BadColorMatrix(e)
+    }
+}
@@ -0,0 +1,112 @@
+use clap::ValueEnum;
+use exr::meta::attribute::AttributeValue;
+use rayon::prelude::*;
+
+use crate::{
+    buffer::RgbImage,
+    error::{Raw2ExrError, Result},
+    metadata::Metadata,
+};
+
+/// Saturation-based speed constant, from ISO 12232
+const SATURATION_CONSTANT: f32 = 78.0;
+
+/// Lens transmittance and vignetting factor of the exposure equation
+const LENS_FACTOR: f32 = 0.65;
+
+/// Reflected-light meter calibration constant, in cd/m²
+const METER_CONSTANT: f32 = 12.5;
+
+/// Where middle grey lands in scene-referred images
+pub const MIDDLE_GREY: f32 = 0.18;
+
+/// What 1.0 means in output pixels, before exposure compensation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Normalization {
+    /// The sensor white level
+    #[default]
+    WhiteLevel,
+    /// A metered grey card shot at the reference exposure lands at 0.18
+    MiddleGrey,
+    /// One cd/m² of scene luminance
+    Absolute,
+}
+
+/// Camera settings that middle grey normalization is relative to. Defaults follow the sunny 16 rule.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReferenceExposure {
+    pub iso_speed: f32,
+    /// Exposure time, in seconds
+    pub shutter: f32,
+    /// F-number
+    pub aperture: f32,
+}
+
+impl Default for ReferenceExposure {
+    fn default() -> Self {
+        ReferenceExposure {
+            iso_speed: 100.0,
+            shutter: 0.01,
+            aperture: 16.0,
+        }
+    }
+}
+
+impl ReferenceExposure {
+    /// Luminance of a metered grey card, in cd/m²
+    pub fn grey_luminance(&self) -> f32 {
+        METER_CONSTANT * self.aperture.powi(2) / (self.shutter * self.iso_speed)
+    }
+}
+
+/// Scales linear images so plates from different cameras and settings line up
+#[derive(Debug, Clone, Default)]
+pub struct ExposureScaler {
+    /// Compensation, in stops
+    pub stops: f32,
+    pub normalization: Normalization,
+    pub reference: ReferenceExposure,
+}
+
+impl ExposureScaler {
+    /// Factor from normalized pixels, where 1.0 is the white level, to output pixels
+    pub fn scale(&self, metadata: &Metadata) -> Result<f32> {
+        let normalization = match self.normalization {
+            Normalization::WhiteLevel => 1.0,
+            Normalization::MiddleGrey => {
+                saturation_luminance(metadata)? * MIDDLE_GREY / self.reference.grey_luminance()
+            }
+            Normalization::Absolute => saturation_luminance(metadata)?,
+        };
+        Ok(normalization * self.stops.exp2())
+    }
+
+    /// Scale the image, and record the factor as `raw2exr/exposureScale`
+    pub fn apply(&self, image: &mut RgbImage, metadata: &mut Metadata) -> Result<()> {
+        let scale = self.scale(metadata)?;
+        metadata.extra.push((
+            "raw2exr/exposureScale".to_string(),
+            AttributeValue::F32(scale),
+        ));
+        if scale != 1.0 {
+            for plane in [&mut image.red, &mut image.green, &mut image.blue] {
+                plane.par_iter_mut().for_each(|value| *value *= scale);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Scene luminance, in cd/m², that reaches the white level with the settings the file was shot with
+pub fn saturation_luminance(metadata: &Metadata) -> Result<f32> {
+    match (metadata.iso_speed, metadata.exposure, metadata.aperture) {
+        (Some(iso_speed), Some(shutter), Some(aperture))
+            if (iso_speed > 0.0) & (shutter > 0.0) & (aperture > 0.0) =>
+        {
+            Ok(SATURATION_CONSTANT * aperture.powi(2) / (LENS_FACTOR * shutter * iso_speed))
+        }
+        _ => Err(Raw2ExrError::InvalidInput(
+            "normalization needs ISO speed, exposure time and aperture from EXIF".to_string(),
+        )),
+    }
+}
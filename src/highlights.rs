@@ -0,0 +1,249 @@
+use clap::ValueEnum;
+
+use crate::{
+    buffer::{Mosaic, RgbImage},
+    demosaic::mirror,
+};
+
+/// Normalized level at which a photosite of a single frame is considered clipped
+pub const CLIP_LEVEL: f32 = 1.0;
+
+/// How far, in pixels, reconstruction looks for unclipped colors
+const SEARCH_RADIUS: isize = 8;
+
+/// What happens to pixels where the sensor reached its white level
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum HighlightMode {
+    /// Clamp all channels at the white point before white balance, so clipped areas stay neutral
+    Clip,
+    /// Leave values above white as they are, clipped areas may turn magenta
+    #[default]
+    Unclip,
+    /// Keep the brightness of unclipped values with the neutral color of clipped ones
+    Blend,
+    /// Rebuild clipped channels from unclipped ones, using color ratios of nearby unclipped pixels
+    Reconstruct,
+}
+
+/// Deals with clipped highlights in white balanced camera RGB
+#[derive(Debug, Clone, Default)]
+pub struct Highlights {
+    pub mode: HighlightMode,
+}
+
+impl Highlights {
+    /// Whether `apply` needs a clipping mask
+    pub fn needs_mask(&self) -> bool {
+        self.mode == HighlightMode::Reconstruct
+    }
+
+    /// Apply the mode to an image that clipped at `clip_level` before being white balanced with given coefficients. `mask` is required by reconstruct, which falls back to blend without it.
+    pub fn apply(
+        &self,
+        image: &mut RgbImage,
+        mask: Option<&RgbImage>,
+        coefficients: [f32; 3],
+        clip_level: f32,
+    ) {
+        // Clipping level of each channel once white balanced, and where the first one clips
+        let levels = coefficients.map(|coefficient| coefficient * clip_level);
+        let white = levels.iter().copied().fold(f32::INFINITY, f32::min);
+
+        *image = match (self.mode, mask) {
+            (HighlightMode::Unclip, _) => return,
+            (HighlightMode::Clip, _) => RgbImage::from_fn(image.width, image.height, |x, y| {
+                let (r, g, b) = image.pixel(x, y);
+                (r.min(white), g.min(white), b.min(white))
+            }),
+            (HighlightMode::Blend, _) | (HighlightMode::Reconstruct, None) => {
+                RgbImage::from_fn(image.width, image.height, |x, y| {
+                    let (r, g, b) = image.pixel(x, y);
+                    let [r, g, b] = blend([r, g, b], white);
+                    (r, g, b)
+                })
+            }
+            (HighlightMode::Reconstruct, Some(mask)) => reconstruct(image, mask, levels, white),
+        };
+    }
+}
+
+/// Mask of a normalized mosaic before white balance: a channel is 1.0 where a photosite of its color within one CFA period reached `clip_level`
+pub fn mosaic_mask(mosaic: &Mosaic, clip_level: f32) -> RgbImage {
+    let radius = (mosaic.cfa.width.max(mosaic.cfa.height) / 2) as isize;
+    RgbImage::from_fn(mosaic.width, mosaic.height, |x, y| {
+        let mut clipped = [0.0; 3];
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                let ax = mirror(x as isize + dx, mosaic.width);
+                let ay = mirror(y as isize + dy, mosaic.height);
+                let color = mosaic.color_at(ax, ay);
+                if (color < 3) && (mosaic.at(ax, ay) >= clip_level) {
+                    clipped[color] = 1.0;
+                }
+            }
+        }
+        (clipped[0], clipped[1], clipped[2])
+    })
+}
+
+/// Mask of a normalized image before white balance: a channel is 1.0 where it reached `clip_level`
+pub fn rgb_mask(image: &RgbImage, clip_level: f32) -> RgbImage {
+    let flag = |value: f32| f32::from(u8::from(value >= clip_level));
+    RgbImage::from_fn(image.width, image.height, |x, y| {
+        let (r, g, b) = image.pixel(x, y);
+        (flag(r), flag(g), flag(b))
+    })
+}
+
+/// Clamp at white, then scale back up to the sum of the unclipped values
+fn blend(pixel: [f32; 3], white: f32) -> [f32; 3] {
+    if pixel.iter().all(|&value| value <= white) {
+        return pixel;
+    }
+    let clipped = pixel.map(|value| value.min(white));
+    let sum = pixel.iter().sum::<f32>();
+    let clipped_sum = clipped.iter().sum::<f32>();
+    if clipped_sum > 0.0 {
+        clipped.map(|value| value * sum / clipped_sum)
+    } else {
+        clipped
+    }
+}
+
+/// Estimate every clipped channel from an unclipped one, green if possible, times their ratio around unclipped neighbours
+fn reconstruct(image: &RgbImage, mask: &RgbImage, levels: [f32; 3], white: f32) -> RgbImage {
+    let flags = |x: usize, y: usize| {
+        let (r, g, b) = mask.pixel(x, y);
+        [r > 0.0, g > 0.0, b > 0.0]
+    };
+
+    RgbImage::from_fn(image.width, image.height, |x, y| {
+        let (r, g, b) = image.pixel(x, y);
+        let mut pixel = [r, g, b];
+        let clipped = flags(x, y);
+        let Some(reference) = [1, 0, 2].into_iter().find(|&c| !clipped[c]) else {
+            let [r, g, b] = blend(pixel, white);
+            return (r, g, b);
+        };
+
+        for color in (0..3).filter(|&c| clipped[c]) {
+            let mut sum = 0.0;
+            let mut reference_sum = 0.0;
+            for dy in -SEARCH_RADIUS..=SEARCH_RADIUS {
+                for dx in -SEARCH_RADIUS..=SEARCH_RADIUS {
+                    let ax = mirror(x as isize + dx, image.width);
+                    let ay = mirror(y as isize + dy, image.height);
+                    if flags(ax, ay).contains(&true) {
+                        continue;
+                    }
+                    let (nr, ng, nb) = image.pixel(ax, ay);
+                    let neighbour = [nr, ng, nb];
+                    if neighbour[reference] > 0.0 {
+                        sum += neighbour[color];
+                        reference_sum += neighbour[reference];
+                    }
+                }
+            }
+
+            // Clipped channels are at least at their clipping level
+            let floor = pixel[color].min(levels[color]);
+            pixel[color] = if reference_sum > 0.0 {
+                (pixel[reference] * sum / reference_sum).max(floor)
+            } else {
+                floor
+            };
+        }
+        (pixel[0], pixel[1], pixel[2])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demosaic::tests::mosaic;
+
+    /// White balance multiplying red by 2 and blue by 1.5, so green clips first
+    const COEFFICIENTS: [f32; 3] = [2.0, 1.0, 1.5];
+
+    fn apply(mode: HighlightMode, image: &mut RgbImage, mask: Option<&RgbImage>) {
+        Highlights { mode }.apply(image, mask, COEFFICIENTS, CLIP_LEVEL);
+    }
+
+    fn assert_pixel(actual: (f32, f32, f32), expected: (f32, f32, f32)) {
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-6;
+        assert!(
+            close(actual.0, expected.0)
+                && close(actual.1, expected.1)
+                && close(actual.2, expected.2),
+            "{actual:?}, expected {expected:?}"
+        );
+    }
+
+    #[test]
+    fn clip_and_unclip() {
+        let pixels = [(3.0, 1.2, 0.5), (0.8, 0.6, 0.4)];
+        let image = RgbImage::from_fn(2, 1, |x, _| pixels[x]);
+
+        let mut unclipped = image.clone();
+        apply(HighlightMode::Unclip, &mut unclipped, None);
+        assert_eq!(unclipped.red, image.red);
+        assert_eq!(unclipped.green, image.green);
+
+        let mut clipped = image;
+        apply(HighlightMode::Clip, &mut clipped, None);
+        assert_pixel(clipped.pixel(0, 0), (1.0, 1.0, 0.5));
+        assert_pixel(clipped.pixel(1, 0), pixels[1]);
+    }
+
+    #[test]
+    fn blend_keeps_the_brightness() {
+        let pixels = [(3.0, 1.2, 0.5), (0.8, 0.6, 0.4)];
+        let mut image = RgbImage::from_fn(2, 1, |x, _| pixels[x]);
+        apply(HighlightMode::Blend, &mut image, None);
+        // Clamped to (1, 1, 0.5) then scaled from 2.5 back to 4.7
+        assert_pixel(image.pixel(0, 0), (1.88, 1.88, 0.94));
+        assert_pixel(image.pixel(1, 0), pixels[1]);
+    }
+
+    #[test]
+    fn reconstruct_follows_the_color_of_neighbours() {
+        // Red is twice green and blue half of it everywhere, but red clipped in the middle
+        let mut image = RgbImage::from_fn(5, 5, |x, y| {
+            let green = if (x, y) == (2, 2) {
+                1.1
+            } else {
+                0.2 + 0.05 * x as f32
+            };
+            ((2.0 * green).min(2.0), green, 0.5 * green)
+        });
+        let mask = RgbImage::from_fn(5, 5, |x, y| {
+            (f32::from(u8::from((x, y) == (2, 2))), 0.0, 0.0)
+        });
+
+        apply(HighlightMode::Reconstruct, &mut image, Some(&mask));
+        assert_pixel(image.pixel(2, 2), (2.2, 1.1, 0.55));
+        assert_pixel(image.pixel(1, 3), (0.5, 0.25, 0.125));
+    }
+
+    #[test]
+    fn reconstruct_without_mask_blends() {
+        let mut image = RgbImage::from_fn(1, 1, |_, _| (3.0, 1.2, 0.5));
+        apply(HighlightMode::Reconstruct, &mut image, None);
+        assert_pixel(image.pixel(0, 0), (1.88, 1.88, 0.94));
+    }
+
+    #[test]
+    fn mosaic_mask_covers_one_cfa_period() {
+        let sensor = mosaic("RGGB", 6, 6, |x, y| {
+            [if (x, y) == (2, 2) { 1.0 } else { 0.5 }, 0.5, 0.5]
+        });
+        let mask = mosaic_mask(&sensor, CLIP_LEVEL);
+        for y in 0..6 {
+            for x in 0..6 {
+                let near = (1..=3).contains(&x) && (1..=3).contains(&y);
+                let expected = if near { 1.0 } else { 0.0 };
+                assert_eq!(mask.pixel(x, y), (expected, 0.0, 0.0), "({x}, {y})");
+            }
+        }
+    }
+}
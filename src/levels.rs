@@ -0,0 +1,125 @@
+use std::f32::consts::PI;
+
+use clap::ValueEnum;
+
+use crate::buffer::plane_from_fn;
+
+/// Resolution levels stored in a tiled file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum LevelMode {
+    /// Full resolution only
+    #[default]
+    Single,
+    /// Full resolution plus halvings of both dimensions at once
+    Mip,
+    /// Full resolution plus every combination of horizontal and vertical halvings
+    Rip,
+}
+
+/// Filter used to downsample lower resolution levels
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Filter {
+    /// Plain average of the covered pixels
+    #[default]
+    Box,
+    /// Linear falloff, a little smoother than box
+    Triangle,
+    /// 3-lobed Lanczos, sharpest but can ring around edges
+    Lanczos3,
+}
+
+impl Filter {
+    /// Radius of the kernel, in pixels of the smaller image
+    fn support(self) -> f32 {
+        match self {
+            Filter::Box => 0.5,
+            Filter::Triangle => 1.0,
+            Filter::Lanczos3 => 3.0,
+        }
+    }
+
+    fn weight(self, x: f32) -> f32 {
+        let x = x.abs();
+        match self {
+            Filter::Box => f32::from(u8::from(x <= 0.5)),
+            Filter::Triangle => (1.0 - x).max(0.0),
+            Filter::Lanczos3 if x < 3.0 => sinc(x) * sinc(x / 3.0),
+            Filter::Lanczos3 => 0.0,
+        }
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Number of levels for a dimension, halving and rounding down until 1
+pub fn level_count(size: usize) -> usize {
+    (usize::BITS - size.max(1).leading_zeros()) as usize
+}
+
+/// Size of a dimension at given level, rounded down
+pub fn level_size(size: usize, level: usize) -> usize {
+    (size >> level).max(1)
+}
+
+/// Resize a plane with given filter, one axis at a time
+pub fn resize(
+    plane: &[f32],
+    width: usize,
+    height: usize,
+    new_width: usize,
+    new_height: usize,
+    filter: Filter,
+) -> Vec<f32> {
+    let columns = weights(width, new_width, filter);
+    let horizontal = plane_from_fn(new_width, height, |x, y| {
+        let (start, weights) = &columns[x];
+        let row = &plane[width * y..width * (y + 1)];
+        weights
+            .iter()
+            .zip(&row[*start..])
+            .map(|(weight, value)| weight * value)
+            .sum::<f32>()
+    });
+
+    let rows = weights(height, new_height, filter);
+    plane_from_fn(new_width, new_height, |x, y| {
+        let (start, weights) = &rows[y];
+        weights
+            .iter()
+            .enumerate()
+            .map(|(i, weight)| weight * horizontal[new_width * (start + i) + x])
+            .sum::<f32>()
+    })
+}
+
+/// For every output position, first source position and normalized weights of the source positions it covers
+fn weights(size: usize, new_size: usize, filter: Filter) -> Vec<(usize, Vec<f32>)> {
+    let scale = (size as f32 / new_size as f32).max(1.0);
+    let radius = filter.support() * scale;
+    (0..new_size)
+        .map(|i| {
+            let center = (i as f32 + 0.5) * size as f32 / new_size as f32 - 0.5;
+            let start = ((center - radius).ceil().max(0.0) as usize).min(size - 1);
+            let end = ((center + radius).floor().max(0.0) as usize).min(size - 1);
+            let mut weights: Vec<f32> = (start..=end)
+                .map(|j| filter.weight((j as f32 - center) / scale))
+                .collect();
+            let sum = weights.iter().sum::<f32>();
+            if sum.abs() > f32::EPSILON {
+                weights.iter_mut().for_each(|weight| *weight /= sum);
+            } else {
+                // Kernel fell between samples, take the nearest one
+                weights = vec![0.0; end - start + 1];
+                let nearest = (center.round().max(0.0) as usize).clamp(start, end);
+                weights[nearest - start] = 1.0;
+            }
+            (start, weights)
+        })
+        .collect()
+}
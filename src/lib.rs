@@ -0,0 +1,22 @@
+pub mod align;
+pub mod batch;
+pub mod buffer;
+pub mod calibrate;
+pub mod color;
+pub mod decode;
+pub mod demosaic;
+pub mod encode;
+pub mod error;
+pub mod exposure;
+pub mod highlights;
+pub mod levels;
+pub mod merge;
+pub mod metadata;
+pub mod normalize;
+pub mod orientation;
+pub mod pipeline;
+pub mod stack;
+pub mod white_balance;
+
+pub use error::Raw2ExrError;
+pub use pipeline::{Pipeline, Report};
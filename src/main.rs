@@ -1,235 +1,288 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, process::ExitCode};
 
 use clap::Parser;
-use color_stuff::representations::CIEXYZCoords;
-use exr::{
-    image::{Encoding, Image, Layer, SpecificChannels},
-    math::Vec2,
-    meta::attribute::Chromaticities,
-    prelude::{IntegerBounds, LayerAttributes, WritableImage},
+use raw2exr::{
+    align::{AlignMethod, Aligner},
+    batch::{Batch, ExistingPolicy, Job, Outcome},
+    calibrate::Calibrator,
+    color::{ColorTransform, OutputSpace},
+    demosaic::{DemosaicMethod, Demosaicer},
+    encode::{CropMode, ExrCompression, ExrEncoder, Precision, HALF_MAX},
+    exposure::{ExposureScaler, Normalization, ReferenceExposure},
+    highlights::{HighlightMode, Highlights},
+    levels::{Filter, LevelMode},
+    normalize::{FloatLevels, Normalizer},
+    orientation::{OrientationMode, Orienter},
+    stack::{Combiner, Stacker},
+    white_balance::{WhiteBalance, WhiteBalancer},
+    Pipeline, Raw2ExrError,
 };
-use itertools::Itertools;
-use nalgebra::SMatrix;
-use rawloader::{decode_file, RawImageData};
-
-type Matrix3x3f = SMatrix<f32, 3, 3>;
-type Matrix3x1f = SMatrix<f32, 3, 1>;
-
-// TODO: How should black and white levels be treated here ? Offset ? Linear map ?
 
 #[derive(Parser)]
 struct App {
-    /// Path to camera raw file
-    raw: PathBuf,
-    /// Path to output OpenEXR file
-    exr: PathBuf,
+    /// Camera raw files, directories or glob patterns. `<RAW> <EXR>` also works for a single file
+    #[arg(required = true)]
+    inputs: Vec<PathBuf>,
+    /// Path to output OpenEXR file, for a single input
+    #[arg(short, long, conflicts_with = "output_dir")]
+    output: Option<PathBuf>,
+    /// Directory to write OpenEXR files in, next to each input if not given
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+    /// Output file name. {stem}, {ext} and {dir} are replaced by the input's file stem, extension and subdirectory within --output-dir
+    #[arg(long, default_value = "{stem}.exr")]
+    name_template: String,
+    /// Also look for raw files in subdirectories of input directories
+    #[arg(short, long)]
+    recursive: bool,
+    /// What to do when an output file already exists
+    #[arg(long, value_enum, default_value_t)]
+    existing: ExistingPolicy,
+    /// Merge all inputs, shot with different exposures, into a single HDR file named after the first one
+    #[arg(long)]
+    merge: bool,
+    /// Stack all inputs of the same scene into a single file named after the first one, combining samples this way
+    #[arg(long, value_enum, conflicts_with = "merge")]
+    stack: Option<Combiner>,
+    /// Rejection threshold of --stack sigma-clip, in standard deviations
+    #[arg(long, default_value_t = 3.0)]
+    sigma: f32,
+    /// Dark frame, a raw file or an EXR with a "CFA Mosaic" layer, subtracted from every input before demosaicing
+    #[arg(long)]
+    dark: Option<PathBuf>,
+    /// Flat field, a raw file or an EXR with a "CFA Mosaic" layer, every input is divided by it before demosaicing
+    #[arg(long)]
+    flat: Option<PathBuf>,
+    /// How frames are aligned before merging or stacking
+    #[arg(long, value_enum, default_value_t)]
+    align: AlignMethod,
+    /// Correct perspective and rotation when aligning, not only translation
+    #[arg(long)]
+    homography: bool,
+    /// Number of files converted at the same time
+    #[arg(short, long, default_value_t = 1)]
+    jobs: usize,
+    /// Demosaicing algorithm
+    #[arg(long, value_enum, default_value_t)]
+    demosaic: DemosaicMethod,
+    /// How black and white levels apply to floating-point raw data
+    #[arg(long, value_enum, default_value_t)]
+    float_levels: FloatLevels,
+    /// What happens to pixels where the sensor clipped
+    #[arg(long, value_enum, default_value_t)]
+    highlights: HighlightMode,
+    /// Also write a "Clipping Mask" layer, 1.0 in channels that reached the white level
+    #[arg(long)]
+    mask_layer: bool,
+    /// Exposure compensation, in stops
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    exposure: f32,
+    /// What 1.0 means in output pixels: the sensor white level, a 0.18 middle grey relative to the reference exposure, or 1 cd/m²
+    #[arg(long, value_enum, default_value_t)]
+    normalize: Normalization,
+    /// ISO speed of the reference exposure for middle grey normalization
+    #[arg(long, default_value_t = ReferenceExposure::default().iso_speed)]
+    reference_iso: f32,
+    /// Exposure time, in seconds, of the reference exposure
+    #[arg(long, default_value_t = ReferenceExposure::default().shutter)]
+    reference_shutter: f32,
+    /// F-number of the reference exposure
+    #[arg(long, default_value_t = ReferenceExposure::default().aperture)]
+    reference_aperture: f32,
+    /// White balance: as-shot, none, daylight or custom:R,G,B
+    #[arg(long, default_value = "as-shot")]
+    white_balance: WhiteBalance,
+    /// Color space of output pixels
+    #[arg(long, value_enum, default_value_t)]
+    output_space: OutputSpace,
+    /// EXR compression
+    #[arg(long, value_enum, default_value_t)]
+    compression: ExrCompression,
+    /// Sample type of output pixels
+    #[arg(long, value_enum, default_value_t)]
+    precision: Precision,
+    /// Write tiles of this size, in pixels, instead of scanlines
+    #[arg(long)]
+    tile_size: Option<usize>,
+    /// Lower resolution levels, written tiled
+    #[arg(long, value_enum, default_value_t)]
+    levels: LevelMode,
+    /// Downsampling filter for lower resolution levels
+    #[arg(long, value_enum, default_value_t)]
+    level_filter: Filter,
+    /// Also write the black-subtracted CFA mosaic as a lossless 32-bit float "CFA Mosaic" layer, for re-demosaicing later
+    #[arg(long)]
+    mosaic_layer: bool,
+    /// Whether the sensor area outside the camera's crops is written
+    #[arg(long, value_enum, default_value_t)]
+    crop: CropMode,
+    /// Turn the image upright following the camera orientation, or keep sensor orientation
+    #[arg(long, value_enum, default_value_t)]
+    orientation: OrientationMode,
 }
 
-fn main() {
-    let args = App::parse();
-
-    let image = decode_file(args.raw).unwrap();
-
-    let red_max = image.whitelevels[0] as f32;
-    let green_max = image.whitelevels[1] as f32;
-    let blue_max = image.whitelevels[2] as f32;
-
-    // Throwing out last component, don't know what it's for really
-    let m = image.cam_to_xyz();
-    let cam_to_xyz = Matrix3x3f::new(
-        m[0][0], m[0][1], m[0][2], m[1][0], m[1][1], m[1][2], m[2][0], m[2][1], m[2][2],
-    );
-
-    let mut red = Vec::with_capacity(image.width * image.height);
-    let mut green = Vec::with_capacity(image.width * image.height);
-    let mut blue = Vec::with_capacity(image.width * image.height);
-
-    // Demosaicing is a damn headache
-    if let RawImageData::Integer(data) = image.data {
-        for (index, (y, x)) in (0..image.height)
-            .cartesian_product(0..image.width)
-            .enumerate()
-        {
-            // Load adjacent pixels
-            let mut adjacent_pixels = [None; 8];
-
-            // Check individually if pixels exist before adding them
-            // Top Left
-            if (x != 0) & (y != 0) {
-                // Not in top left corner of image
-                adjacent_pixels[0] = Some((data[index - image.width - 1] as f32, (x - 1, y - 1)));
-            }
-
-            // Top Middle
-            if y != 0 {
-                // Not on topmost row
-                adjacent_pixels[1] = Some((data[index - image.width] as f32, (x, y - 1)));
-            }
-
-            // Top Right
-            if (x != image.width - 1) & (y != 0) {
-                // Not in top right corner of image
-                adjacent_pixels[2] = Some((data[index - image.width + 1] as f32, (x + 1, y - 1)));
-            }
-
-            // Left
-            if x != 0 {
-                // Not on leftmost column
-                adjacent_pixels[3] = Some((data[index - 1] as f32, (x - 1, y)));
-            }
-
-            // Right
-            if x != image.width - 1 {
-                // Not on rightmost column
-                adjacent_pixels[4] = Some((data[index + 1] as f32, (x + 1, y)));
-            }
-
-            // Bottom Left
-            if (x != 0) & (y != image.height - 1) {
-                // Not on bottom left
-                adjacent_pixels[5] = Some((data[index + image.width - 1] as f32, (x - 1, y + 1)));
-            }
-
-            // Bottom Middle
-            if y != image.height - 1 {
-                // Not on bottommost row
-                adjacent_pixels[6] = Some((data[index + image.width] as f32, (x, y + 1)));
-            }
+impl App {
+    /// Single explicit output, either from `--output` or the legacy `<RAW> <EXR>` form
+    fn single_output(&mut self) -> Option<PathBuf> {
+        if self.output.is_some() {
+            return self.output.take();
+        }
+        let legacy = (self.output_dir.is_none())
+            && (self.inputs.len() == 2)
+            && self.inputs[1]
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("exr"));
+        if legacy {
+            self.inputs.pop()
+        } else {
+            None
+        }
+    }
+}
 
-            // Bottom Right
-            if (x != image.width - 1) & (y != image.height - 1) {
-                // Not on bottom left
-                adjacent_pixels[7] = Some((data[index + image.width + 1] as f32, (x + 1, y + 1)));
-            }
+fn main() -> ExitCode {
+    let mut args = App::parse();
+
+    let mut pipeline = Pipeline {
+        normalizer: Normalizer {
+            float_levels: args.float_levels,
+        },
+        stacker: Stacker {
+            combiner: args.stack.unwrap_or_default(),
+            sigma: args.sigma,
+        },
+        aligner: Aligner {
+            method: args.align,
+            homography: args.homography,
+        },
+        white_balancer: WhiteBalancer {
+            mode: args.white_balance,
+        },
+        demosaicer: Demosaicer {
+            method: args.demosaic,
+        },
+        highlights: Highlights {
+            mode: args.highlights,
+        },
+        exposure: ExposureScaler {
+            stops: args.exposure,
+            normalization: args.normalize,
+            reference: ReferenceExposure {
+                iso_speed: args.reference_iso,
+                shutter: args.reference_shutter,
+                aperture: args.reference_aperture,
+            },
+        },
+        color: ColorTransform {
+            output: args.output_space,
+        },
+        orienter: Orienter {
+            mode: args.orientation,
+        },
+        encoder: ExrEncoder {
+            compression: args.compression,
+            precision: args.precision,
+            tile_size: args.tile_size,
+            levels: args.levels,
+            filter: args.level_filter,
+            crop: args.crop,
+            mosaic_layer: args.mosaic_layer,
+            mask_layer: args.mask_layer,
+        },
+        ..Default::default()
+    };
 
-            // Pixel sums
-            let mut red_sum = 0.0;
-            let mut green_sum = 0.0;
-            let mut blue_sum = 0.0;
+    pipeline.calibrator = match Calibrator::from_files(
+        &pipeline.decoder,
+        &pipeline.normalizer,
+        args.dark.as_deref(),
+        args.flat.as_deref(),
+    ) {
+        Ok(calibrator) => calibrator,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            return ExitCode::from(e.exit_code());
+        }
+    };
 
-            // Pixel counts
-            let mut red_count = 0;
-            let mut green_count = 0;
-            let mut blue_count = 0;
+    let batch = Batch {
+        output_dir: args.output_dir.clone(),
+        template: args.name_template.clone(),
+        recursive: args.recursive,
+        existing: args.existing,
+        parallel_files: args.jobs,
+        combined: args.merge || args.stack.is_some(),
+    };
 
-            for (value, (x, y)) in adjacent_pixels.into_iter().flatten() {
-                match image.cfa.color_at(y, x) {
-                    0 => {
-                        // Red
-                        red_sum += value;
-                        red_count += 1;
-                    }
-                    1 => {
-                        // Green
-                        green_sum += value;
-                        green_count += 1;
-                    }
-                    2 => {
-                        // Blue
-                        blue_sum += value;
-                        blue_count += 1;
-                    }
-                    _ => panic!(),
+    let jobs = match args.single_output() {
+        Some(exr) if args.inputs.len() == 1 => Ok(vec![Job {
+            raw: args.inputs[0].clone(),
+            exr,
+        }]),
+        // Only the first output is used when merging or stacking
+        Some(exr) if args.merge || args.stack.is_some() => {
+            batch.jobs(&args.inputs).map(|mut jobs| {
+                if let Some(first) = jobs.first_mut() {
+                    first.exr = exr;
                 }
-            }
-
-            // Average pixels
-            let red_avg = red_sum / red_count as f32 / red_max;
-            let green_avg = green_sum / green_count as f32 / green_max;
-            let blue_avg = blue_sum / blue_count as f32 / blue_max;
-
-            // Select outputs
-            let red_output;
-            let green_output;
-            let blue_output;
+                jobs
+            })
+        }
+        Some(_) => Err(Raw2ExrError::InvalidInput(
+            "--output only works with a single input".to_string(),
+        )),
+        None => batch.jobs(&args.inputs),
+    };
+    let jobs = match jobs {
+        Ok(jobs) => jobs,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            return ExitCode::from(e.exit_code());
+        }
+    };
 
-            // Use direct component
-            match image.cfa.color_at(y, x) {
-                0 => {
-                    // Red
-                    red_output = data[index] as f32 / red_max;
-                    green_output = green_avg;
-                    blue_output = blue_avg;
+    // Report every failure, exit with the code of the first one
+    let mut exit_code = ExitCode::SUCCESS;
+    let mut failed = false;
+    let outcomes: Vec<(&Job, Outcome)> = if args.merge {
+        jobs.first()
+            .map(|first| (first, batch.run_merged(&pipeline, &jobs)))
+            .into_iter()
+            .collect()
+    } else if args.stack.is_some() {
+        jobs.first()
+            .map(|first| (first, batch.run_stacked(&pipeline, &jobs)))
+            .into_iter()
+            .collect()
+    } else {
+        jobs.iter().zip(batch.run(&pipeline, &jobs)).collect()
+    };
+    for (job, outcome) in outcomes {
+        match outcome {
+            Outcome::Converted(report) => {
+                if let Some(e) = report.xyz_fallback {
+                    eprintln!(
+                        "{}: {e}, writing XYZ instead of camera RGB",
+                        job.exr.display()
+                    );
                 }
-                1 => {
-                    // Green
-                    red_output = red_avg;
-                    green_output = data[index] as f32 / green_max;
-                    blue_output = blue_avg;
+                if report.clamped > 0 {
+                    eprintln!(
+                        "{}: {} samples out of half float range, clamped to ±{HALF_MAX}",
+                        job.exr.display(),
+                        report.clamped
+                    );
                 }
-                2 => {
-                    // Blue
-                    red_output = red_avg;
-                    green_output = green_avg;
-                    blue_output = data[index] as f32 / blue_max;
+            }
+            Outcome::Skipped => eprintln!("Skipped {}, already exists", job.exr.display()),
+            Outcome::Failed(e) => {
+                eprintln!("Error: {e}");
+                if !failed {
+                    exit_code = ExitCode::from(e.exit_code());
+                    failed = true;
                 }
-                _ => panic!(),
             }
-
-            red.push(red_output);
-            green.push(green_output);
-            blue.push(blue_output);
         }
-    } else {
-        unimplemented!()
-    }
-
-    // Convert CAM to XYZ matrix into chromaticities by "probing" colors
-    let red_point = Matrix3x1f::new(red_max, 0.0, 0.0);
-    let green_point = Matrix3x1f::new(0.0, green_max, 0.0);
-    let blue_point = Matrix3x1f::new(0.0, 0.0, blue_max);
-    let white_point = Matrix3x1f::new(red_max, green_max, blue_max);
-
-    // These conversions shouldn't fail unless provided info is wrong
-    let red_xyy = CIEXYZCoords::from(cam_to_xyz * red_point)
-        .try_xyy()
-        .unwrap();
-    let green_xyy = CIEXYZCoords::from(cam_to_xyz * green_point)
-        .try_xyy()
-        .unwrap();
-    let blue_xyy = CIEXYZCoords::from(cam_to_xyz * blue_point)
-        .try_xyy()
-        .unwrap();
-    let white_xyy = CIEXYZCoords::from(cam_to_xyz * white_point)
-        .try_xyy()
-        .unwrap();
-
-    let pixels_fn = |pos: Vec2<usize>| {
-        (
-            red[image.width * pos.y() + pos.x()],
-            green[image.width * pos.y() + pos.x()],
-            blue[image.width * pos.y() + pos.x()],
-        )
-    };
-
-    let layer = Layer::new(
-        (image.width, image.height),
-        LayerAttributes::named("RAW Image"),
-        Encoding::SMALL_FAST_LOSSLESS,
-        SpecificChannels::rgb(pixels_fn),
-    );
-
-    let mut exr_image = Image::from_layer(layer);
-    exr_image.attributes.pixel_aspect = 1.0;
-    exr_image.attributes.display_window =
-        crops_size_to_bounds(image.crops, image.width, image.height);
-    exr_image.attributes.chromaticities = Some(Chromaticities {
-        red: red_xyy.coords.into(),
-        green: green_xyy.coords.into(),
-        blue: blue_xyy.coords.into(),
-        white: white_xyy.coords.into(),
-    });
-
-    exr_image.write().to_file(args.exr).unwrap();
-}
-
-fn crops_size_to_bounds(crops: [usize; 4], width: usize, height: usize) -> IntegerBounds {
-    let top = crops[0];
-    let right = crops[1];
-    let bottom = crops[2];
-    let left = crops[3];
-    IntegerBounds {
-        position: Vec2(left as i32, top as i32),
-        size: Vec2(width - left - right, height - top - bottom),
     }
+    exit_code
 }
@@ -0,0 +1,83 @@
+use crate::{
+    error::{Raw2ExrError, Result},
+    highlights::CLIP_LEVEL,
+    metadata::Metadata,
+    normalize::{combine_frames, Normalized},
+};
+
+/// Light gathered by the sensor for a given scene, relative between frames: exposure time times ISO speed over the squared f-number
+pub fn exposure_factor(metadata: &Metadata) -> Option<f32> {
+    let factor = metadata.exposure? * metadata.iso_speed? / metadata.aperture?.powi(2);
+    (factor.is_finite() && (factor > 0.0)).then_some(factor)
+}
+
+/// How much a normalized sample is trusted, falling to 0 at black and at the clipping level
+pub fn weight(value: f32) -> f32 {
+    let half = CLIP_LEVEL / 2.0;
+    (value.min(CLIP_LEVEL - value) / half).clamp(0.0, 1.0)
+}
+
+/// Merge frames of the same scene shot with given exposure factors into the first one, scaled to its exposure.
+///
+/// Every sample is the weighted average of the frames scaled to the first exposure. Where no frame can be trusted, the shortest exposure is used for bright samples and the longest one for dark samples. Returns the merged frame and its clipping level.
+pub fn merge(frames: Vec<Normalized>, exposures: &[f32]) -> Result<(Normalized, f32)> {
+    if frames.is_empty() || (frames.len() != exposures.len()) {
+        return Err(Raw2ExrError::InvalidInput(
+            "expected one exposure per frame to merge".to_string(),
+        ));
+    }
+
+    let scales: Vec<f32> = exposures
+        .iter()
+        .map(|exposure| exposures[0] / exposure)
+        .collect();
+    let by_exposure = |a: &usize, b: &usize| exposures[*a].total_cmp(&exposures[*b]);
+    let shortest = (0..exposures.len()).min_by(by_exposure).unwrap_or(0);
+    let longest = (0..exposures.len()).max_by(by_exposure).unwrap_or(0);
+
+    let merged = combine_frames(frames, |samples| {
+        let mut sum = 0.0;
+        let mut weights = 0.0;
+        for (&value, scale) in samples.iter().zip(&scales) {
+            let weight = weight(value);
+            sum += weight * value * scale;
+            weights += weight;
+        }
+        if weights > 0.0 {
+            sum / weights
+        } else if samples[shortest] >= CLIP_LEVEL / 2.0 {
+            samples[shortest] * scales[shortest]
+        } else {
+            samples[longest] * scales[longest]
+        }
+    })?;
+    Ok((merged, CLIP_LEVEL * scales[shortest]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::RgbImage;
+
+    #[test]
+    fn brackets_merge_to_scene_values() {
+        // Scene in units of the first exposure, which is four times longer than the second one
+        let scene = [0.01, 0.1, 0.3, 0.9, 1.5, 3.0, 4.0];
+        let frame = |exposure: f32| {
+            Normalized::Linear(RgbImage::from_fn(scene.len(), 1, |x, _| {
+                let value = (scene[x] * exposure).min(CLIP_LEVEL);
+                (value, value, value)
+            }))
+        };
+
+        let (merged, clip_level) = merge(vec![frame(1.0), frame(0.25)], &[4.0, 1.0]).unwrap();
+        assert_eq!(clip_level, 4.0 * CLIP_LEVEL);
+        let Normalized::Linear(merged) = merged else {
+            panic!("expected an RGB image");
+        };
+        for (x, expected) in scene.into_iter().enumerate() {
+            let (value, _, _) = merged.pixel(x, 0);
+            assert!((value - expected).abs() < 1e-6, "{value} for {expected}");
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use exif::{Exif, In, Tag, Value};
+use exr::{
+    meta::attribute::{AttributeValue, Text},
+    prelude::LayerAttributes,
+};
+use rawloader::RawImage;
+
+/// Attribute with the orientation of the sensor data
+pub const ORIENTATION_ATTRIBUTE: &str = "raw2exr/orientation";
+
+/// Attribute with the crops (top, right, bottom, left) of the sensor data
+pub const CROPS_ATTRIBUTE: &str = "raw2exr/crops";
+
+/// Capture information gathered from the raw file, written as EXR header attributes
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub lens_make: Option<String>,
+    pub lens_model: Option<String>,
+    pub owner: Option<String>,
+    /// `YYYY:MM:DD hh:mm:ss`, in local time
+    pub capture_date: Option<String>,
+    /// UTC minus local time, in seconds
+    pub utc_offset: Option<f32>,
+    /// Exposure time, in seconds
+    pub exposure: Option<f32>,
+    /// F-number
+    pub aperture: Option<f32>,
+    pub iso_speed: Option<f32>,
+    /// Focus distance, in meters
+    pub focus: Option<f32>,
+    /// Focal length, in millimeters
+    pub focal_length: Option<f32>,
+    /// Everything else rawloader knows about the file, written as `raw2exr/*` attributes
+    pub extra: Vec<(String, AttributeValue)>,
+}
+
+impl Metadata {
+    /// Gather metadata from rawloader and, when the file has some, EXIF tags. Missing or unreadable EXIF is not an error.
+    pub fn read(path: &Path, image: &RawImage) -> Metadata {
+        let mut metadata = Metadata::default();
+
+        if let Some(exif) = read_exif(path) {
+            metadata.camera_make = ascii(&exif, Tag::Make);
+            metadata.camera_model = ascii(&exif, Tag::Model);
+            metadata.lens_make = ascii(&exif, Tag::LensMake);
+            metadata.lens_model = ascii(&exif, Tag::LensModel);
+            metadata.owner = ascii(&exif, Tag::Copyright).or_else(|| ascii(&exif, Tag::Artist));
+            metadata.capture_date = ascii(&exif, Tag::DateTimeOriginal);
+            metadata.utc_offset = ascii(&exif, Tag::OffsetTimeOriginal)
+                .and_then(|offset| parse_offset(&offset))
+                .map(|seconds| -seconds);
+            metadata.exposure = number(&exif, Tag::ExposureTime);
+            metadata.aperture = number(&exif, Tag::FNumber);
+            metadata.iso_speed = number(&exif, Tag::PhotographicSensitivity);
+            metadata.focus = number(&exif, Tag::SubjectDistance);
+            metadata.focal_length = number(&exif, Tag::FocalLength);
+        }
+
+        // rawloader always knows the camera, even without EXIF
+        if metadata.camera_make.is_none() {
+            metadata.camera_make = Some(image.make.clone());
+        }
+        if metadata.camera_model.is_none() {
+            metadata.camera_model = Some(image.model.clone());
+        }
+
+        let text = |string: String| Text::new_or_none(string).map(AttributeValue::Text);
+
+        let extra = [
+            ("cleanMake", text(image.clean_make.clone())),
+            ("cleanModel", text(image.clean_model.clone())),
+            ("cfa", text(image.cfa.name.clone())),
+            ("orientation", text(format!("{:?}", image.orientation))),
+            (
+                "componentsPerPixel",
+                Some(AttributeValue::I32(image.cpp as i32)),
+            ),
+            (
+                "whiteLevels",
+                text(join(&image.whitelevels.map(|level| level as f32))),
+            ),
+            (
+                "blackLevels",
+                text(join(&image.blacklevels.map(|level| level as f32))),
+            ),
+            ("wbCoeffs", text(join(&image.wb_coeffs))),
+            ("xyzToCam", text(join(image.xyz_to_cam.as_flattened()))),
+            ("crops", text(join(&image.crops.map(|crop| crop as f32)))),
+        ];
+        metadata.extra = extra
+            .into_iter()
+            .filter_map(|(name, value)| Some((format!("raw2exr/{name}"), value?)))
+            .collect();
+
+        metadata
+    }
+
+    /// Remove an attribute of `extra`, once the pixels no longer match it
+    pub fn remove_extra(&mut self, name: &str) {
+        self.extra.retain(|(extra, _)| extra != name);
+    }
+
+    /// Replace the crops attribute, for crops moved along with the pixels
+    pub fn set_crops(&mut self, crops: [usize; 4]) {
+        let value = AttributeValue::Text(Text::from(join(&crops.map(|crop| crop as f32)).as_str()));
+        match self
+            .extra
+            .iter_mut()
+            .find(|(name, _)| name == CROPS_ATTRIBUTE)
+        {
+            Some(extra) => extra.1 = value,
+            None => self.extra.push((CROPS_ATTRIBUTE.to_string(), value)),
+        }
+    }
+
+    /// Fill standard EXR attributes and add custom ones
+    pub fn apply(&self, attributes: &mut LayerAttributes) {
+        let text = |string: &Option<String>| string.as_ref().and_then(Text::new_or_none);
+
+        attributes.owner = text(&self.owner);
+        attributes.capture_date = text(&self.capture_date);
+        attributes.utc_offset = self.utc_offset;
+        attributes.exposure = self.exposure;
+        attributes.aperture = self.aperture;
+        attributes.iso_speed = self.iso_speed;
+        attributes.focus = self.focus;
+        attributes.software_name = Text::new_or_none("raw2exr");
+
+        // Camera and lens attributes standardized by OpenEXR 3.3
+        let named = [
+            (
+                "cameraMake",
+                text(&self.camera_make).map(AttributeValue::Text),
+            ),
+            (
+                "cameraModel",
+                text(&self.camera_model).map(AttributeValue::Text),
+            ),
+            ("lensMake", text(&self.lens_make).map(AttributeValue::Text)),
+            (
+                "lensModel",
+                text(&self.lens_model).map(AttributeValue::Text),
+            ),
+            (
+                "nominalFocalLength",
+                self.focal_length.map(AttributeValue::F32),
+            ),
+        ];
+        for (name, value) in named {
+            if let Some(value) = value {
+                attributes.other.insert(Text::from(name), value);
+            }
+        }
+
+        for (name, value) in &self.extra {
+            if let Some(name) = Text::new_or_none(name) {
+                attributes.other.insert(name, value.clone());
+            }
+        }
+    }
+}
+
+/// Comma separated list of numbers
+fn join(values: &[f32]) -> String {
+    values
+        .iter()
+        .map(|value| value.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn read_exif(path: &Path) -> Option<Exif> {
+    let file = File::open(path).ok()?;
+    exif::Reader::new()
+        .read_from_container(&mut BufReader::new(file))
+        .ok()
+}
+
+/// First string of an ASCII tag, if not empty
+fn ascii(exif: &Exif, tag: Tag) -> Option<String> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(strings) => strings
+            .first()
+            .map(|bytes| String::from_utf8_lossy(bytes).trim().to_string())
+            .filter(|string| !string.is_empty()),
+        _ => None,
+    }
+}
+
+/// First value of a numeric tag
+fn number(exif: &Exif, tag: Tag) -> Option<f32> {
+    let value = match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(values) => values.first()?.to_f64(),
+        Value::SRational(values) => values.first()?.to_f64(),
+        Value::Short(values) => *values.first()? as f64,
+        Value::Long(values) => *values.first()? as f64,
+        _ => return None,
+    };
+    value.is_finite().then_some(value as f32)
+}
+
+/// Seconds ahead of UTC from an EXIF offset such as `+09:00`
+fn parse_offset(offset: &str) -> Option<f32> {
+    let (sign, rest) = match offset.as_bytes().first()? {
+        b'+' => (1.0, &offset[1..]),
+        b'-' => (-1.0, &offset[1..]),
+        _ => return None,
+    };
+    let (hours, minutes) = rest.split_once(':')?;
+    let hours: f32 = hours.parse().ok()?;
+    let minutes: f32 = minutes.parse().ok()?;
+    Some(sign * (hours * 3600.0 + minutes * 60.0))
+}
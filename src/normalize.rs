@@ -0,0 +1,362 @@
+use clap::ValueEnum;
+use rawloader::{RawImage, RawImageData, CFA};
+use rayon::prelude::*;
+
+use crate::{
+    buffer::{Mosaic, RgbImage},
+    error::{Raw2ExrError, Result},
+};
+
+/// Maps raw sensor values to a linear [0, 1] range by subtracting black levels and scaling against white levels
+#[derive(Debug, Clone, Default)]
+pub struct Normalizer {
+    pub float_levels: FloatLevels,
+}
+
+/// Normalized sensor data, before white balance
+#[derive(Debug, Clone)]
+pub enum Normalized {
+    /// One sample per photosite, still to be demosaiced. Boxed, as the CFA is large and frames are moved around.
+    Mosaic(Box<Mosaic>),
+    /// Already demosaiced
+    Linear(RgbImage),
+}
+
+impl Normalized {
+    pub fn width(&self) -> usize {
+        match self {
+            Normalized::Mosaic(mosaic) => mosaic.width,
+            Normalized::Linear(image) => image.width,
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        match self {
+            Normalized::Mosaic(mosaic) => mosaic.height,
+            Normalized::Linear(image) => image.height,
+        }
+    }
+
+    /// Sample planes: the mosaic, or red, green and blue
+    pub fn planes(&self) -> Vec<&[f32]> {
+        match self {
+            Normalized::Mosaic(mosaic) => vec![mosaic.data.as_slice()],
+            Normalized::Linear(image) => vec![
+                image.red.as_slice(),
+                image.green.as_slice(),
+                image.blue.as_slice(),
+            ],
+        }
+    }
+
+    pub fn planes_mut(&mut self) -> Vec<&mut Vec<f32>> {
+        match self {
+            Normalized::Mosaic(mosaic) => vec![&mut mosaic.data],
+            Normalized::Linear(image) => vec![&mut image.red, &mut image.green, &mut image.blue],
+        }
+    }
+
+    /// Whether both have the same size, kind and CFA pattern, so their samples line up
+    pub fn same_layout(&self, other: &Normalized) -> bool {
+        let same_kind = match (self, other) {
+            (Normalized::Mosaic(a), Normalized::Mosaic(b)) => {
+                (a.cfa.width == b.cfa.width)
+                    & (a.cfa.height == b.cfa.height)
+                    & (a.pattern() == b.pattern())
+            }
+            (Normalized::Linear(_), Normalized::Linear(_)) => true,
+            _ => false,
+        };
+        same_kind & (self.width() == other.width()) & (self.height() == other.height())
+    }
+}
+
+/// Combine frames of the same layout sample by sample into the first one.
+///
+/// `combine` gets the samples of every frame at one position, in frame order, in a buffer it may reorder.
+pub fn combine_frames(
+    mut frames: Vec<Normalized>,
+    combine: impl Fn(&mut [f32]) -> f32 + Sync,
+) -> Result<Normalized> {
+    if frames.is_empty() {
+        return Err(Raw2ExrError::InvalidInput(
+            "no frames to combine".to_string(),
+        ));
+    }
+    if let Some(index) = frames
+        .iter()
+        .position(|frame| !frame.same_layout(&frames[0]))
+    {
+        return Err(Raw2ExrError::UnsupportedLayout(format!(
+            "frame {} doesn't have the size and CFA pattern of the first one",
+            index + 1
+        )));
+    }
+
+    let combined: Vec<Vec<f32>> = {
+        let planes: Vec<Vec<&[f32]>> = frames.iter().map(Normalized::planes).collect();
+        (0..planes[0].len())
+            .map(|plane| {
+                (0..planes[0][plane].len())
+                    .into_par_iter()
+                    .map_init(
+                        || Vec::with_capacity(planes.len()),
+                        |samples, index| {
+                            samples.clear();
+                            samples.extend(planes.iter().map(|frame| frame[plane][index]));
+                            combine(samples)
+                        },
+                    )
+                    .collect()
+            })
+            .collect()
+    };
+
+    let mut output = frames.swap_remove(0);
+    for (plane, values) in output.planes_mut().into_iter().zip(combined) {
+        *plane = values;
+    }
+    Ok(output)
+}
+
+/// Meaning of the four slots of `blacklevels` and `whitelevels`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LevelOrder {
+    /// One slot per color, in RGBE order, as rawloader documents them
+    #[default]
+    Color,
+    /// One slot per position within a 2x2 CFA period, row by row, as DNG and PEF files store them
+    Position,
+}
+
+/// How black and white levels apply to floating-point raw data
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum FloatLevels {
+    /// Consider data already normalized if the white level or the brightest sample is at most 1.0
+    #[default]
+    Auto,
+    /// Data is already in [0, 1], black and white levels are ignored
+    Normalized,
+    /// Apply black and white levels the same way as for integer data
+    Levels,
+}
+
+impl FloatLevels {
+    /// Whether given float samples should be used as they are
+    pub fn is_normalized(self, image: &RawImage, data: &[f32]) -> bool {
+        match self {
+            FloatLevels::Auto => {
+                image.whitelevels.iter().all(|&white| white <= 1)
+                    || data.iter().copied().fold(f32::NEG_INFINITY, f32::max) <= 1.0
+            }
+            FloatLevels::Normalized => true,
+            FloatLevels::Levels => false,
+        }
+    }
+}
+
+impl Normalizer {
+    /// Normalize any image, a mosaic when there is one component per pixel
+    pub fn normalize_any(&self, image: &RawImage, order: LevelOrder) -> Result<Normalized> {
+        if image.cpp > 1 {
+            self.normalize_linear(image).map(Normalized::Linear)
+        } else {
+            self.normalize(image, order)
+                .map(|mosaic| Normalized::Mosaic(Box::new(mosaic)))
+        }
+    }
+
+    /// Normalize a mosaic, with one sample per photosite, reading levels in given order
+    pub fn normalize(&self, image: &RawImage, order: LevelOrder) -> Result<Mosaic> {
+        check_sample_count(image)?;
+        check_cfa(&image.cfa)?;
+
+        let data = self.samples(image, |index| {
+            level_index(&image.cfa, order, index % image.width, index / image.width)
+        });
+
+        Ok(Mosaic {
+            width: image.width,
+            height: image.height,
+            cfa: image.cfa.clone(),
+            data,
+        })
+    }
+
+    /// Normalize an image that is already demosaiced (LinearRaw), with 3 or more components per pixel.
+    ///
+    /// The first three components map to red, green and blue, anything after that is dropped.
+    pub fn normalize_linear(&self, image: &RawImage) -> Result<RgbImage> {
+        check_sample_count(image)?;
+        let cpp = image.cpp;
+        if cpp < 3 {
+            return Err(Raw2ExrError::UnsupportedLayout(format!(
+                "{cpp} components per pixel"
+            )));
+        }
+
+        // There are only four level slots, components past them are dropped anyway
+        let samples = self.samples(image, |index| (index % cpp).min(3));
+
+        let mut output = RgbImage::new(image.width, image.height);
+        for (index, pixel) in samples.chunks_exact(cpp).enumerate() {
+            output.red[index] = pixel[0];
+            output.green[index] = pixel[1];
+            output.blue[index] = pixel[2];
+        }
+        Ok(output)
+    }
+
+    /// All samples of the image, with `(value - black) / (white - black)` applied using the levels at the index given by `level_of` for each sample
+    fn samples(&self, image: &RawImage, level_of: impl Fn(usize) -> usize + Sync) -> Vec<f32> {
+        let scale = |index: usize, value: f32| {
+            let level = level_of(index);
+            let black = image.blacklevels[level] as f32;
+            let white = image.whitelevels[level] as f32;
+            (value - black) / (white - black)
+        };
+
+        match &image.data {
+            RawImageData::Integer(raw) => raw
+                .par_iter()
+                .enumerate()
+                .map(|(index, &value)| scale(index, value as f32))
+                .collect(),
+            RawImageData::Float(raw) => {
+                if self.float_levels.is_normalized(image, raw) {
+                    raw.clone()
+                } else {
+                    raw.par_iter()
+                        .enumerate()
+                        .map(|(index, &value)| scale(index, value))
+                        .collect()
+                }
+            }
+        }
+    }
+}
+
+/// Make sure there is exactly one sample per component of every pixel
+fn check_sample_count(image: &RawImage) -> Result<()> {
+    let expected = image.width * image.height * image.cpp;
+    let actual = match &image.data {
+        RawImageData::Integer(data) => data.len(),
+        RawImageData::Float(data) => data.len(),
+    };
+    if actual != expected {
+        return Err(Raw2ExrError::UnsupportedLayout(format!(
+            "expected {expected} samples, found {actual}"
+        )));
+    }
+    Ok(())
+}
+
+/// Make sure the CFA pattern only has red, green and blue photosites
+fn check_cfa(cfa: &CFA) -> Result<()> {
+    if (cfa.width == 0) | (cfa.height == 0) {
+        return Err(Raw2ExrError::UnsupportedLayout(
+            "no CFA pattern for single-component data".to_string(),
+        ));
+    }
+    for y in 0..cfa.height {
+        for x in 0..cfa.width {
+            if cfa.color_at(y, x) > 2 {
+                return Err(Raw2ExrError::UnsupportedLayout(format!(
+                    "CFA pattern {} has colors other than red, green and blue",
+                    cfa.name
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Index in `blacklevels` and `whitelevels` that applies to the photosite at given position.
+///
+/// Per-position levels only describe 2x2 patterns, larger ones fall back to the first slot.
+pub fn level_index(cfa: &CFA, order: LevelOrder, x: usize, y: usize) -> usize {
+    match order {
+        LevelOrder::Color => cfa.color_at(y, x),
+        LevelOrder::Position if (cfa.width == 2) & (cfa.height == 2) => (y % 2) * 2 + x % 2,
+        LevelOrder::Position => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rawloader::Orientation;
+
+    use super::*;
+
+    /// Single-component image with given CFA pattern and levels
+    fn raw(pattern: &str, blacks: [u16; 4], whites: [u16; 4], data: Vec<u16>) -> RawImage {
+        RawImage {
+            make: String::new(),
+            model: String::new(),
+            clean_make: String::new(),
+            clean_model: String::new(),
+            width: 2,
+            height: data.len() / 2,
+            cpp: 1,
+            wb_coeffs: [1.0; 4],
+            whitelevels: whites,
+            blacklevels: blacks,
+            xyz_to_cam: [[0.0; 3]; 4],
+            cfa: CFA::new(pattern),
+            crops: [0; 4],
+            blackareas: Vec::new(),
+            orientation: Orientation::Normal,
+            data: RawImageData::Integer(data),
+        }
+    }
+
+    #[test]
+    fn color_order_uses_the_green_slot_for_both_greens() {
+        let cfa = CFA::new("RGGB");
+        let slots = [(0, 0), (1, 0), (0, 1), (1, 1)]
+            .map(|(x, y)| level_index(&cfa, LevelOrder::Color, x, y));
+        assert_eq!(slots, [0, 1, 1, 2]);
+    }
+
+    #[test]
+    fn position_order_follows_the_cfa_period() {
+        for pattern in ["RGGB", "BGGR", "GRBG", "GBRG"] {
+            let cfa = CFA::new(pattern);
+            let slots = [(0, 0), (1, 0), (0, 1), (1, 1), (2, 2), (3, 3)]
+                .map(|(x, y)| level_index(&cfa, LevelOrder::Position, x, y));
+            assert_eq!(slots, [0, 1, 2, 3, 0, 3], "{pattern}");
+        }
+    }
+
+    #[test]
+    fn black_from_masked_areas_applies_to_every_green() {
+        // rawloader leaves the E slot at 0 when averaging masked areas
+        let image = raw("RGGB", [100, 100, 100, 0], [4100; 4], vec![1100; 4]);
+        let mosaic = Normalizer::default()
+            .normalize(&image, LevelOrder::Color)
+            .unwrap();
+        assert_eq!(mosaic.data, vec![0.25; 4]);
+    }
+
+    #[test]
+    fn per_position_levels_apply_to_their_photosite() {
+        let image = raw(
+            "BGGR",
+            [100, 200, 300, 400],
+            [1100, 1200, 1300, 1400],
+            vec![350, 450, 550, 650],
+        );
+        let mosaic = Normalizer::default()
+            .normalize(&image, LevelOrder::Position)
+            .unwrap();
+        assert_eq!(mosaic.data, vec![0.25; 4]);
+    }
+
+    #[test]
+    fn components_past_the_fourth_are_dropped() {
+        let mut image = raw("RGGB", [0; 4], [1000; 4], vec![250, 500, 750, 1000, 2000]);
+        (image.width, image.height, image.cpp) = (1, 1, 5);
+        let rgb = Normalizer::default().normalize_linear(&image).unwrap();
+        assert_eq!(rgb.pixel(0, 0), (0.25, 0.5, 0.75));
+    }
+}
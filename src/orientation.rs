@@ -0,0 +1,312 @@
+use clap::ValueEnum;
+use exr::meta::attribute::AttributeValue;
+use rawloader::Orientation;
+
+use crate::{
+    buffer::{plane_from_fn, square_cfa, Mosaic, RgbImage},
+    error::{Raw2ExrError, Result},
+    metadata::{Metadata, ORIENTATION_ATTRIBUTE},
+};
+
+/// What to do with the orientation the camera recorded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OrientationMode {
+    /// Rotate and flip pixels so the image is upright
+    #[default]
+    Apply,
+    /// Keep sensor orientation, record the EXIF orientation as `raw2exr/exifOrientation`
+    Keep,
+}
+
+/// Turns images upright according to the camera orientation
+#[derive(Debug, Clone, Default)]
+pub struct Orienter {
+    pub mode: OrientationMode,
+}
+
+impl Orienter {
+    /// Oriented image and mosaic, with crops (top, right, bottom, left) moved along
+    pub fn apply(
+        &self,
+        image: RgbImage,
+        mosaic: Option<Mosaic>,
+        crops: [usize; 4],
+        orientation: Orientation,
+    ) -> Result<(RgbImage, Option<Mosaic>, [usize; 4])> {
+        match self.mode {
+            OrientationMode::Apply => Ok((
+                self.apply_rgb(image, orientation),
+                mosaic
+                    .map(|mosaic| orient_mosaic(&mosaic, orientation))
+                    .transpose()?,
+                orient_crops(crops, orientation),
+            )),
+            OrientationMode::Keep => Ok((image, mosaic, crops)),
+        }
+    }
+
+    /// Oriented image, for images that go along the main one such as masks
+    pub fn apply_rgb(&self, image: RgbImage, orientation: Orientation) -> RgbImage {
+        match self.mode {
+            OrientationMode::Apply => orient(&image, orientation),
+            OrientationMode::Keep => image,
+        }
+    }
+
+    /// Record the orientation when it was not applied. When it was, the sensor orientation no longer applies and `crops` are the oriented ones.
+    pub fn record(&self, metadata: &mut Metadata, orientation: Orientation, crops: [usize; 4]) {
+        match self.mode {
+            OrientationMode::Apply => {
+                metadata.remove_extra(ORIENTATION_ATTRIBUTE);
+                metadata.set_crops(crops);
+            }
+            OrientationMode::Keep => metadata.extra.push((
+                "raw2exr/exifOrientation".to_string(),
+                AttributeValue::I32(exif_value(orientation).into()),
+            )),
+        }
+    }
+}
+
+/// EXIF orientation tag value, 1 to 8
+pub fn exif_value(orientation: Orientation) -> u16 {
+    match orientation {
+        Orientation::Normal | Orientation::Unknown => 1,
+        Orientation::HorizontalFlip => 2,
+        Orientation::Rotate180 => 3,
+        Orientation::VerticalFlip => 4,
+        Orientation::Transpose => 5,
+        Orientation::Rotate90 => 6,
+        Orientation::Transverse => 7,
+        Orientation::Rotate270 => 8,
+    }
+}
+
+/// Whether width and height are swapped
+pub fn is_transposing(orientation: Orientation) -> bool {
+    matches!(
+        orientation,
+        Orientation::Transpose
+            | Orientation::Rotate90
+            | Orientation::Transverse
+            | Orientation::Rotate270
+    )
+}
+
+/// Size of an image once oriented
+pub fn oriented_size(width: usize, height: usize, orientation: Orientation) -> (usize, usize) {
+    if is_transposing(orientation) {
+        (height, width)
+    } else {
+        (width, height)
+    }
+}
+
+/// Position in a source image of given size of the pixel at (x, y) once oriented
+pub fn source_position(
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    orientation: Orientation,
+) -> (usize, usize) {
+    let (w, h) = (width, height);
+    match orientation {
+        Orientation::Normal | Orientation::Unknown => (x, y),
+        Orientation::HorizontalFlip => (w - 1 - x, y),
+        Orientation::Rotate180 => (w - 1 - x, h - 1 - y),
+        Orientation::VerticalFlip => (x, h - 1 - y),
+        Orientation::Transpose => (y, x),
+        Orientation::Rotate90 => (y, h - 1 - x),
+        Orientation::Transverse => (w - 1 - y, h - 1 - x),
+        Orientation::Rotate270 => (w - 1 - y, x),
+    }
+}
+
+/// Image rotated and flipped to be upright. Rotations are clockwise, as in EXIF.
+pub fn orient(image: &RgbImage, orientation: Orientation) -> RgbImage {
+    let (width, height) = oriented_size(image.width, image.height, orientation);
+    RgbImage::from_fn(width, height, |x, y| {
+        let (sx, sy) = source_position(x, y, image.width, image.height, orientation);
+        image.pixel(sx, sy)
+    })
+}
+
+/// Mosaic rotated and flipped to be upright, with its CFA pattern rebuilt from the new positions of the photosites
+pub fn orient_mosaic(mosaic: &Mosaic, orientation: Orientation) -> Result<Mosaic> {
+    if matches!(orientation, Orientation::Normal | Orientation::Unknown) {
+        return Ok(mosaic.clone());
+    }
+    let (width, height) = oriented_size(mosaic.width, mosaic.height, orientation);
+    let source = |x, y| source_position(x, y, mosaic.width, mosaic.height, orientation);
+
+    let (pattern_width, pattern_height) =
+        oriented_size(mosaic.cfa.width, mosaic.cfa.height, orientation);
+    let unsupported = || {
+        Raw2ExrError::UnsupportedLayout(format!(
+            "cannot orient a mosaic with a {}x{} CFA pattern",
+            mosaic.cfa.width, mosaic.cfa.height
+        ))
+    };
+    if (pattern_width > width) | (pattern_height > height) {
+        return Err(unsupported());
+    }
+    let mut name = String::new();
+    for y in 0..pattern_height {
+        for x in 0..pattern_width {
+            let (sx, sy) = source(x, y);
+            name.push(match mosaic.color_at(sx, sy) {
+                0 => 'R',
+                1 => 'G',
+                2 => 'B',
+                _ => 'E',
+            });
+        }
+    }
+    let cfa = square_cfa(&name, pattern_width, pattern_height).ok_or_else(unsupported)?;
+
+    Ok(Mosaic {
+        width,
+        height,
+        cfa,
+        data: plane_from_fn(width, height, |x, y| {
+            let (sx, sy) = source(x, y);
+            mosaic.at(sx, sy)
+        }),
+    })
+}
+
+/// Crops (top, right, bottom, left) of the oriented image
+pub fn orient_crops(crops: [usize; 4], orientation: Orientation) -> [usize; 4] {
+    let [top, right, bottom, left] = crops;
+    match orientation {
+        Orientation::Normal | Orientation::Unknown => crops,
+        Orientation::HorizontalFlip => [top, left, bottom, right],
+        Orientation::Rotate180 => [bottom, left, top, right],
+        Orientation::VerticalFlip => [bottom, right, top, left],
+        Orientation::Transpose => [left, bottom, right, top],
+        Orientation::Rotate90 => [left, top, right, bottom],
+        Orientation::Transverse => [right, top, left, bottom],
+        Orientation::Rotate270 => [right, bottom, left, top],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use exr::meta::attribute::Text;
+
+    use super::*;
+    use crate::{demosaic::tests::mosaic, metadata::CROPS_ATTRIBUTE};
+
+    /// Check positions against the oriented image of a 3x2 sensor whose pixels are 0 to 5, row by row, and the CFA pattern of an oriented RGGB mosaic
+    fn check(orientation: Orientation, rows: &[&[usize]], pattern: &str) {
+        let (width, height) = oriented_size(3, 2, orientation);
+        assert_eq!((width, height), (rows[0].len(), rows.len()));
+        for (y, row) in rows.iter().enumerate() {
+            for (x, &index) in row.iter().enumerate() {
+                assert_eq!(
+                    source_position(x, y, 3, 2, orientation),
+                    (index % 3, index / 3),
+                    "({x}, {y})"
+                );
+            }
+        }
+
+        // Cropping then orienting is orienting then cropping with oriented crops
+        let image = RgbImage::from_fn(9, 7, |x, y| ((9 * y + x) as f32, 0.0, 0.0));
+        let crop = |image: &RgbImage, [top, right, bottom, left]: [usize; 4]| {
+            image.crop(
+                left,
+                top,
+                image.width - left - right,
+                image.height - top - bottom,
+            )
+        };
+        let crops = [1, 2, 3, 4];
+        assert_eq!(
+            orient(&crop(&image, crops), orientation).red,
+            crop(
+                &orient(&image, orientation),
+                orient_crops(crops, orientation)
+            )
+            .red
+        );
+
+        let sensor = mosaic("RGGB", 4, 6, |x, y| [(4 * y + x) as f32; 3]);
+        let oriented = orient_mosaic(&sensor, orientation).unwrap();
+        assert_eq!(oriented.pattern(), pattern);
+        for y in 0..oriented.height {
+            for x in 0..oriented.width {
+                let (sx, sy) = source_position(x, y, 4, 6, orientation);
+                assert_eq!(oriented.at(x, y), sensor.at(sx, sy));
+                assert_eq!(oriented.color_at(x, y), sensor.color_at(sx, sy));
+            }
+        }
+    }
+
+    #[test]
+    fn normal() {
+        check(Orientation::Normal, &[&[0, 1, 2], &[3, 4, 5]], "RGGB");
+    }
+
+    #[test]
+    fn horizontal_flip() {
+        check(
+            Orientation::HorizontalFlip,
+            &[&[2, 1, 0], &[5, 4, 3]],
+            "GRBG",
+        );
+    }
+
+    #[test]
+    fn rotate_180() {
+        check(Orientation::Rotate180, &[&[5, 4, 3], &[2, 1, 0]], "BGGR");
+    }
+
+    #[test]
+    fn vertical_flip() {
+        check(Orientation::VerticalFlip, &[&[3, 4, 5], &[0, 1, 2]], "GBRG");
+    }
+
+    #[test]
+    fn transpose() {
+        check(Orientation::Transpose, &[&[0, 3], &[1, 4], &[2, 5]], "RGGB");
+    }
+
+    #[test]
+    fn rotate_90() {
+        check(Orientation::Rotate90, &[&[3, 0], &[4, 1], &[5, 2]], "GRBG");
+    }
+
+    #[test]
+    fn transverse() {
+        check(
+            Orientation::Transverse,
+            &[&[5, 2], &[4, 1], &[3, 0]],
+            "BGGR",
+        );
+    }
+
+    #[test]
+    fn rotate_270() {
+        check(Orientation::Rotate270, &[&[2, 5], &[1, 4], &[0, 3]], "GBRG");
+    }
+
+    #[test]
+    fn applied_orientation_updates_metadata() {
+        let mut metadata = Metadata {
+            extra: vec![
+                (ORIENTATION_ATTRIBUTE.to_string(), AttributeValue::I32(6)),
+                (CROPS_ATTRIBUTE.to_string(), AttributeValue::I32(0)),
+            ],
+            ..Metadata::default()
+        };
+        Orienter::default().record(&mut metadata, Orientation::Rotate90, [4, 1, 2, 3]);
+        assert_eq!(metadata.extra.len(), 1);
+        assert_eq!(metadata.extra[0].0, CROPS_ATTRIBUTE);
+        assert_eq!(
+            metadata.extra[0].1,
+            AttributeValue::Text(Text::from("4, 1, 2, 3"))
+        );
+    }
+}
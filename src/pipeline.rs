@@ -0,0 +1,189 @@
+use std::path::{Path, PathBuf};
+
+use exr::meta::attribute::AttributeValue;
+use rawloader::RawImage;
+
+use crate::{
+    align::Aligner,
+    calibrate::Calibrator,
+    color::{ColorTransform, PrimariesError},
+    decode::Decoder,
+    demosaic::Demosaicer,
+    encode::{ExrEncoder, ExtraLayers},
+    error::{Raw2ExrError, Result},
+    exposure::ExposureScaler,
+    highlights::{mosaic_mask, rgb_mask, Highlights, CLIP_LEVEL},
+    merge::{exposure_factor, merge},
+    metadata::Metadata,
+    normalize::{Normalized, Normalizer},
+    orientation::Orienter,
+    stack::Stacker,
+    white_balance::WhiteBalancer,
+};
+
+/// Things worth telling about a conversion that succeeded, left to the caller to report
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    /// Samples out of half float range, clamped to ±`HALF_MAX`
+    pub clamped: usize,
+    /// Why camera RGB output was written as XYZ instead
+    pub xyz_fallback: Option<PrimariesError>,
+}
+
+/// Full raw to EXR conversion, made of stages that can be configured or called individually
+#[derive(Debug, Clone, Default)]
+pub struct Pipeline {
+    pub decoder: Decoder,
+    pub normalizer: Normalizer,
+    pub calibrator: Calibrator,
+    pub aligner: Aligner,
+    pub stacker: Stacker,
+    pub white_balancer: WhiteBalancer,
+    pub demosaicer: Demosaicer,
+    pub highlights: Highlights,
+    pub exposure: ExposureScaler,
+    pub color: ColorTransform,
+    pub orienter: Orienter,
+    pub encoder: ExrEncoder,
+}
+
+impl Pipeline {
+    /// Convert a single camera raw file to an OpenEXR file
+    pub fn convert(&self, raw: &Path, exr: &Path) -> Result<Report> {
+        let (image, normalized, metadata) = self.load(raw)?;
+        self.develop(&image, normalized, CLIP_LEVEL, metadata, exr)
+    }
+
+    /// Merge camera raw files of the same scene, shot with different exposures, into one HDR OpenEXR file.
+    ///
+    /// Frames are aligned onto the first file. Output is scaled to its exposure, and its metadata, white balance and color matrix are used.
+    pub fn convert_merged(&self, raws: &[PathBuf], exr: &Path) -> Result<Report> {
+        let mut exposures = Vec::with_capacity(raws.len());
+        let (image, mut metadata, mut frames) = self.load_frames(raws, |raw, metadata| {
+            let exposure = exposure_factor(metadata).ok_or_else(|| {
+                Raw2ExrError::InvalidInput(format!(
+                    "{} has no exposure time, ISO speed or aperture to merge with",
+                    raw.display()
+                ))
+            })?;
+            exposures.push(exposure);
+            Ok(())
+        })?;
+
+        self.aligner.align(&mut frames);
+        let (merged, clip_level) = merge(frames, &exposures)?;
+        metadata.extra.push((
+            "raw2exr/mergedFrames".to_string(),
+            AttributeValue::I32(raws.len() as i32),
+        ));
+        self.develop(&image, merged, clip_level, metadata, exr)
+    }
+
+    /// Stack camera raw files of the same scene into one OpenEXR file with less noise.
+    ///
+    /// Frames are aligned onto the first file and combined. Metadata, white balance and color matrix of the first file are used.
+    pub fn convert_stacked(&self, raws: &[PathBuf], exr: &Path) -> Result<Report> {
+        let (image, mut metadata, mut frames) = self.load_frames(raws, |_, _| Ok(()))?;
+
+        self.aligner.align(&mut frames);
+        let stacked = self.stacker.stack(frames)?;
+        metadata.extra.push((
+            "raw2exr/stackedFrames".to_string(),
+            AttributeValue::I32(raws.len() as i32),
+        ));
+        self.develop(&image, stacked, CLIP_LEVEL, metadata, exr)
+    }
+
+    /// Load frames to combine, keeping the raw image and metadata of the first one. `inspect` sees the path and metadata of every frame.
+    fn load_frames(
+        &self,
+        raws: &[PathBuf],
+        mut inspect: impl FnMut(&Path, &Metadata) -> Result<()>,
+    ) -> Result<(RawImage, Metadata, Vec<Normalized>)> {
+        let mut first = None;
+        let mut frames = Vec::with_capacity(raws.len());
+        for raw in raws {
+            let (image, normalized, metadata) = self.load(raw)?;
+            inspect(raw, &metadata)?;
+            frames.push(normalized);
+            first.get_or_insert((image, metadata));
+        }
+        let (image, metadata) =
+            first.ok_or_else(|| Raw2ExrError::InvalidInput("no input files".to_string()))?;
+        Ok((image, metadata, frames))
+    }
+
+    /// Decode, normalize and calibrate a camera raw file, and read its metadata
+    fn load(&self, raw: &Path) -> Result<(RawImage, Normalized, Metadata)> {
+        let image = self.decoder.decode(raw)?;
+        let mut normalized = self
+            .normalizer
+            .normalize_any(&image, self.decoder.level_order(raw))?;
+        self.calibrator.apply(&mut normalized)?;
+        let metadata = Metadata::read(raw, &image);
+        Ok((image, normalized, metadata))
+    }
+
+    /// Every stage after normalization, for data that clips at `clip_level`
+    fn develop(
+        &self,
+        image: &RawImage,
+        normalized: Normalized,
+        clip_level: f32,
+        mut metadata: Metadata,
+        exr: &Path,
+    ) -> Result<Report> {
+        let white_balance = self.white_balancer.coefficients(image);
+        let needs_mask = self.highlights.needs_mask() || self.encoder.mask_layer;
+        let mut original_mosaic = None;
+        let mut mask = None;
+        let mut rgb = match normalized {
+            Normalized::Linear(mut rgb) => {
+                if needs_mask {
+                    mask = Some(rgb_mask(&rgb, clip_level));
+                }
+                self.white_balancer.apply_rgb(&mut rgb, white_balance);
+                rgb
+            }
+            Normalized::Mosaic(mut mosaic) => {
+                if needs_mask {
+                    mask = Some(mosaic_mask(&mosaic, clip_level));
+                }
+                if self.encoder.mosaic_layer {
+                    original_mosaic = Some((*mosaic).clone());
+                }
+                self.white_balancer.apply_mosaic(&mut mosaic, white_balance);
+                self.demosaicer.demosaic(&mosaic)
+            }
+        };
+        self.highlights
+            .apply(&mut rgb, mask.as_ref(), white_balance, clip_level);
+        self.exposure.apply(&mut rgb, &mut metadata)?;
+        let xyz_fallback = self.color.fallback(image, white_balance)?;
+        self.color.apply(&mut rgb, image, white_balance)?;
+        let chromaticities = self.color.chromaticities(image, white_balance)?;
+        let (rgb, original_mosaic, crops) =
+            self.orienter
+                .apply(rgb, original_mosaic, image.crops, image.orientation)?;
+        let mask = mask
+            .filter(|_| self.encoder.mask_layer)
+            .map(|mask| self.orienter.apply_rgb(mask, image.orientation));
+        self.orienter
+            .record(&mut metadata, image.orientation, crops);
+        let clamped = self.encoder.encode(
+            &rgb,
+            ExtraLayers {
+                mosaic: original_mosaic.as_ref(),
+                mask: mask.as_ref(),
+            },
+            crops,
+            chromaticities,
+            &metadata,
+            exr,
+        )?;
+        Ok(Report {
+            clamped,
+            xyz_fallback,
+        })
+    }
+}
@@ -0,0 +1,175 @@
+use clap::ValueEnum;
+
+use crate::{
+    error::Result,
+    normalize::{combine_frames, Normalized},
+};
+
+/// Most rejection passes of the sigma-clipped mean
+const MAX_PASSES: usize = 5;
+
+/// Standard deviation over median absolute deviation, for normally distributed samples
+const MAD_SCALE: f32 = 1.4826;
+
+/// How samples of stacked frames are combined
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Combiner {
+    /// Average, lowest noise but sensitive to outliers such as satellites or hot pixels
+    Mean,
+    /// Middle value, ignores outliers
+    #[default]
+    Median,
+    /// Average of the samples within `sigma` standard deviations of the median, repeated until none is rejected. The deviation is estimated from the median absolute deviation, so outliers don't widen it.
+    SigmaClip,
+}
+
+/// Combines frames of the same scene into one with less noise
+#[derive(Debug, Clone)]
+pub struct Stacker {
+    pub combiner: Combiner,
+    /// Rejection threshold of the sigma-clipped mean, in standard deviations
+    pub sigma: f32,
+}
+
+impl Default for Stacker {
+    fn default() -> Self {
+        Stacker {
+            combiner: Combiner::default(),
+            sigma: 3.0,
+        }
+    }
+}
+
+impl Stacker {
+    /// Combine frames sample by sample into the first one
+    pub fn stack(&self, frames: Vec<Normalized>) -> Result<Normalized> {
+        combine_frames(frames, |samples| self.combine(samples))
+    }
+
+    /// Combine the samples of one position, reordering them
+    pub fn combine(&self, samples: &mut [f32]) -> f32 {
+        match self.combiner {
+            Combiner::Mean => mean(samples),
+            Combiner::Median => median(samples),
+            Combiner::SigmaClip => {
+                let mut kept = samples.len();
+                for _ in 0..MAX_PASSES {
+                    let center = median(&mut samples[..kept]);
+                    let limit = self.sigma * MAD_SCALE * median_deviation(&samples[..kept], center);
+
+                    // Samples are sorted, so the ones kept are a range around the median
+                    let start = samples[..kept].partition_point(|&value| center - value > limit);
+                    let end = samples[..kept].partition_point(|&value| value - center <= limit);
+                    if (end - start == kept) | (end == start) {
+                        break;
+                    }
+                    samples.copy_within(start..end, 0);
+                    kept = end - start;
+                }
+                mean(&samples[..kept])
+            }
+        }
+    }
+}
+
+fn mean(samples: &[f32]) -> f32 {
+    samples.iter().sum::<f32>() / samples.len().max(1) as f32
+}
+
+fn median(samples: &mut [f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    samples.sort_unstable_by(f32::total_cmp);
+    let middle = samples.len() / 2;
+    if samples.len().is_multiple_of(2) {
+        (samples[middle - 1] + samples[middle]) / 2.0
+    } else {
+        samples[middle]
+    }
+}
+
+/// Median of the distances of sorted samples to `center`, which is within their range
+fn median_deviation(sorted: &[f32], center: f32) -> f32 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+
+    // Distances grow going away from the center on either side, so merge both sides in order
+    let mut below = sorted.partition_point(|&value| value < center);
+    let mut above = below;
+    let mut deviations = std::iter::from_fn(|| {
+        let down = below.checked_sub(1).map(|index| center - sorted[index]);
+        let up = sorted.get(above).map(|value| value - center);
+        match (down, up) {
+            (Some(down), Some(up)) if down < up => {
+                below -= 1;
+                Some(down)
+            }
+            (_, Some(up)) => {
+                above += 1;
+                Some(up)
+            }
+            (Some(down), None) => {
+                below -= 1;
+                Some(down)
+            }
+            (None, None) => None,
+        }
+    });
+
+    let middle = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        let low = deviations.nth(middle - 1).unwrap_or(0.0);
+        (low + deviations.next().unwrap_or(low)) / 2.0
+    } else {
+        deviations.nth(middle).unwrap_or(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn combine(combiner: Combiner, samples: &[f32]) -> f32 {
+        let stacker = Stacker {
+            combiner,
+            ..Stacker::default()
+        };
+        stacker.combine(&mut samples.to_vec())
+    }
+
+    #[test]
+    fn mean_and_median() {
+        let samples = [1.0, 4.0, 2.0, 9.0];
+        assert_eq!(combine(Combiner::Mean, &samples), 4.0);
+        assert_eq!(combine(Combiner::Median, &samples), 3.0);
+        assert_eq!(combine(Combiner::Median, &samples[..3]), 2.0);
+    }
+
+    #[test]
+    fn sigma_clip_rejects_a_hot_pixel_in_a_short_stack() {
+        let combined = combine(Combiner::SigmaClip, &[1.0, 1.01, 0.99, 1.02, 1000.0]);
+        assert!((combined - 1.005).abs() < 1e-4, "{combined}");
+    }
+
+    #[test]
+    fn sigma_clip_rejects_outliers_of_identical_samples() {
+        let mut samples = vec![0.5; 10];
+        samples.push(1000.0);
+        assert_eq!(combine(Combiner::SigmaClip, &samples), 0.5);
+    }
+
+    #[test]
+    fn sigma_clip_keeps_noise() {
+        let samples = [0.9, 1.1, 1.0, 0.95, 1.05];
+        assert!((combine(Combiner::SigmaClip, &samples) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn median_deviation_of_sorted_samples() {
+        assert_eq!(median_deviation(&[1.0, 2.0, 3.0, 4.0, 100.0], 3.0), 1.0);
+        assert_eq!(median_deviation(&[1.0, 2.0, 4.0, 8.0], 3.0), 1.5);
+        assert_eq!(median_deviation(&[], 0.0), 0.0);
+    }
+}
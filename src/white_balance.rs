@@ -0,0 +1,211 @@
+use std::str::FromStr;
+
+use rawloader::RawImage;
+use rayon::prelude::*;
+
+use crate::buffer::{Mosaic, RgbImage};
+
+/// CIE XYZ of the D65 illuminant, with Y = 1
+pub const D65_XYZ: [f32; 3] = [0.950_47, 1.0, 1.088_83];
+
+/// Where white balance coefficients come from
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum WhiteBalance {
+    /// Coefficients recorded by the camera, falls back to daylight if missing
+    #[default]
+    AsShot,
+    /// Keep camera-native white
+    None,
+    /// Coefficients that make D65 neutral, computed from the camera color matrix
+    Daylight,
+    /// Given R, G, B multipliers
+    Custom([f32; 3]),
+}
+
+impl FromStr for WhiteBalance {
+    type Err = String;
+
+    /// Parses `as-shot`, `none`, `daylight` or `custom:R,G,B`. Bare `R,G,B` is also accepted as custom.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "as-shot" => Ok(WhiteBalance::AsShot),
+            "none" => Ok(WhiteBalance::None),
+            "daylight" => Ok(WhiteBalance::Daylight),
+            _ => {
+                let values = s.strip_prefix("custom:").unwrap_or(s);
+                let coefficients = values
+                    .split(',')
+                    .map(|value| value.trim().parse::<f32>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| format!("invalid white balance coefficient: {e}"))?;
+                match coefficients[..] {
+                    [r, g, b] if [r, g, b].iter().all(|c| c.is_finite() && (*c > 0.0)) => {
+                        Ok(WhiteBalance::Custom([r, g, b]))
+                    }
+                    _ => Err(format!(
+                        "expected as-shot, none, daylight or custom:R,G,B with three positive numbers, got '{s}'"
+                    )),
+                }
+            }
+        }
+    }
+}
+
+/// Scales color channels so that the chosen white becomes neutral
+#[derive(Debug, Clone, Default)]
+pub struct WhiteBalancer {
+    pub mode: WhiteBalance,
+}
+
+impl WhiteBalancer {
+    /// R, G, B multipliers to apply to normalized data, with green at 1.0
+    pub fn coefficients(&self, image: &RawImage) -> [f32; 3] {
+        match self.mode {
+            WhiteBalance::AsShot => {
+                as_shot(image).unwrap_or_else(|| normalize_green(daylight(image)))
+            }
+            WhiteBalance::None => [1.0; 3],
+            WhiteBalance::Daylight => normalize_green(daylight(image)),
+            WhiteBalance::Custom(coefficients) => normalize_green(coefficients),
+        }
+    }
+
+    pub fn apply_mosaic(&self, mosaic: &mut Mosaic, coefficients: [f32; 3]) {
+        let cfa = &mosaic.cfa;
+        mosaic
+            .data
+            .par_chunks_mut(mosaic.width.max(1))
+            .enumerate()
+            .for_each(|(y, row)| {
+                for (x, value) in row.iter_mut().enumerate() {
+                    let color = cfa.color_at(y, x);
+                    if color < 3 {
+                        *value *= coefficients[color];
+                    }
+                }
+            });
+    }
+
+    pub fn apply_rgb(&self, image: &mut RgbImage, coefficients: [f32; 3]) {
+        let planes = [&mut image.red, &mut image.green, &mut image.blue];
+        for (plane, coefficient) in planes.into_iter().zip(coefficients) {
+            plane.par_iter_mut().for_each(|value| *value *= coefficient);
+        }
+    }
+}
+
+/// Camera coefficients, if all three are usable
+fn as_shot(image: &RawImage) -> Option<[f32; 3]> {
+    let coefficients = [image.wb_coeffs[0], image.wb_coeffs[1], image.wb_coeffs[2]];
+    if coefficients.iter().all(|c| c.is_finite() && (*c > 0.0)) {
+        Some(normalize_green(coefficients))
+    } else {
+        None
+    }
+}
+
+/// Inverse of the camera response to D65
+fn daylight(image: &RawImage) -> [f32; 3] {
+    let mut coefficients = [1.0; 3];
+    for (channel, coefficient) in coefficients.iter_mut().enumerate() {
+        let response: f32 = image.xyz_to_cam[channel]
+            .iter()
+            .zip(D65_XYZ)
+            .map(|(m, w)| m * w)
+            .sum();
+        if response > 0.0 {
+            *coefficient = 1.0 / response;
+        }
+    }
+    coefficients
+}
+
+fn normalize_green(coefficients: [f32; 3]) -> [f32; 3] {
+    coefficients.map(|c| c / coefficients[1])
+}
+
+#[cfg(test)]
+mod tests {
+    use rawloader::{Orientation, RawImageData, CFA};
+
+    use super::*;
+
+    /// Camera responding to D65 with (0.5, 1, 0.8), with given recorded coefficients
+    fn raw(wb_coeffs: [f32; 4]) -> RawImage {
+        RawImage {
+            make: String::new(),
+            model: String::new(),
+            clean_make: String::new(),
+            clean_model: String::new(),
+            width: 0,
+            height: 0,
+            cpp: 1,
+            wb_coeffs,
+            whitelevels: [1; 4],
+            blacklevels: [0; 4],
+            xyz_to_cam: [[0.0, 0.5, 0.0], [0.0, 1.0, 0.0], [0.0, 0.8, 0.0], [0.0; 3]],
+            cfa: CFA::new("RGGB"),
+            crops: [0; 4],
+            blackareas: Vec::new(),
+            orientation: Orientation::Normal,
+            data: RawImageData::Integer(Vec::new()),
+        }
+    }
+
+    fn coefficients(mode: WhiteBalance, image: &RawImage) -> [f32; 3] {
+        WhiteBalancer { mode }.coefficients(image)
+    }
+
+    #[test]
+    fn parse_modes() {
+        assert_eq!("as-shot".parse(), Ok(WhiteBalance::AsShot));
+        assert_eq!("none".parse(), Ok(WhiteBalance::None));
+        assert_eq!("daylight".parse(), Ok(WhiteBalance::Daylight));
+        assert_eq!(
+            "custom:2, 1,1.5".parse(),
+            Ok(WhiteBalance::Custom([2.0, 1.0, 1.5]))
+        );
+        assert_eq!("2,1,1.5".parse(), Ok(WhiteBalance::Custom([2.0, 1.0, 1.5])));
+    }
+
+    #[test]
+    fn reject_bad_input() {
+        for input in [
+            "",
+            "tungsten",
+            "custom:2,1",
+            "2,1,1.5,1",
+            "2,0,1",
+            "2,-1,1",
+            "2,inf,1",
+            "2,x,1",
+        ] {
+            assert!(input.parse::<WhiteBalance>().is_err(), "{input}");
+        }
+    }
+
+    #[test]
+    fn modes_give_green_normalized_coefficients() {
+        let image = raw([4.0, 2.0, 3.0, 0.0]);
+        assert_eq!(coefficients(WhiteBalance::AsShot, &image), [2.0, 1.0, 1.5]);
+        assert_eq!(coefficients(WhiteBalance::None, &image), [1.0; 3]);
+        assert_eq!(
+            coefficients(WhiteBalance::Daylight, &image),
+            [2.0, 1.0, 1.25]
+        );
+        assert_eq!(
+            coefficients(WhiteBalance::Custom([1.0, 2.0, 4.0]), &image),
+            [0.5, 1.0, 2.0]
+        );
+    }
+
+    #[test]
+    fn as_shot_falls_back_to_daylight() {
+        for wb_coeffs in [[f32::NAN, 1.0, 1.0, 0.0], [2.0, 1.0, 0.0, 0.0]] {
+            assert_eq!(
+                coefficients(WhiteBalance::AsShot, &raw(wb_coeffs)),
+                [2.0, 1.0, 1.25]
+            );
+        }
+    }
+}